@@ -1,64 +1,462 @@
-/// The purpose of this module is to pre calculate the embedding cache and store it in an annoy file
+/// The purpose of this module is to pre calculate the embedding cache and store it in an index file
 
-use std::{path::PathBuf, fs::read_dir, sync::Arc};
-use rannoy::Rannoy;
-use tokio::sync::Mutex;
-use crate::embedding::{Embedder, EmbeddingState, Task, CacheItem};
+use std::{path::{Path, PathBuf}, fs::read_dir, sync::Arc, collections::{HashMap, HashSet}, cmp::Reverse, time::{Duration, Instant}};
+use crate::embedding::{Embedder, Cache, EmbeddingState, Task, CacheItem, IndexBackend, Id, Prompt, is_readme, normalize};
+use crate::error::{Result, Error};
+use crate::ignore::Ignore;
+use crate::walk::{WalkFilter, WalkOptions};
 use async_recursion::async_recursion;
+use tokio::{sync::{mpsc, Mutex, Semaphore}, task::spawn_blocking};
 
+mod progress;
+mod estimate;
+use progress::Progress;
+pub use estimate::dry_run;
+
+/// Weight of a README in the vector of its directory, compared to the other children
+const README_WEIGHT: f32 = 3.;
+/// Number of directories read at the same time by the walker
+const WALK_CONCURRENCY: usize = 8;
+/// Number of tasks reading the items and extracting their prompts
+const EXTRACTION_WORKERS: usize = 8;
+/// Maximum number of prompts given to the model in one call, an item's prompts are never split
+const BATCH_SIZE: usize = 64;
+/// Capacity of the channels between the stages of the build
+const CHANNEL_CAPACITY: usize = 256;
+/// Time between two saves of the index during a build
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(120);
+/// Time between two updates of the progress line
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+fn weighted_mean(vectors: &[(f32, Arc<[f32; 384]>)]) -> Option<Arc<[f32; 384]>> {
+    let total: f32 = vectors.iter().map(|(w, _)| w).sum();
+    if total <= 0. {
+        return None;
+    }
+    let mut mean = [0.; 384];
+    for (weight, vector) in vectors {
+        for (m, v) in mean.iter_mut().zip(vector.iter()) {
+            *m += v * weight / total;
+        }
+    }
+    normalize(&mut mean);
+    Some(Arc::new(mean))
+}
+
+/// Directories found by the walker, with their depth and their children, to combine the vectors of their children once they are all embedded
+type Directories = std::sync::Mutex<HashMap<PathBuf, (usize, Vec<PathBuf>)>>;
+
+/// Item found by the walker
+struct Walked {
+    path: PathBuf,
+    /// Directory listing the item, None for the target
+    parent: Option<PathBuf>,
+    /// Files are counted in the progress of their directory
+    is_file: bool,
+    /// Directories whose children are walked only get their last vector, the mean of their children, at the end of the build
+    aggregated: bool
+}
+
+/// Prompts of an item, waiting to be embedded
+struct Extracted {
+    id: Id,
+    item: Walked,
+    prompts: Vec<Prompt>
+}
+
+/// State shared by the stages of a build
+struct Build {
+    embedder: Embedder,
+    level: EmbeddingState,
+    /// Target of the build, the processed directories are recorded for it
+    root: PathBuf,
+    filter: WalkFilter,
+    directories: Directories,
+    /// Directories whose files were all processed by the interrupted build being resumed, their files aren't sent again
+    processed_directories: HashSet<PathBuf>,
+    progress: Progress
+}
+impl Build {
+    /// Counts a processed item, and records its directory once all its files are processed
+    fn item_processed(&self, cache: &Cache, item: &Walked) -> Result<()> {
+        if let Some(directory) = self.progress.processed(item.parent.as_deref(), item.is_file) {
+            cache.add_processed_directory(&self.root, &directory, self.level)?;
+        }
+        Ok(())
+    }
+
+    /// The directory listing an item given new vectors is embedded again at the end of the build.
+    /// It's marked as not embedded until then, so that a build interrupted before does it too
+    fn outdate_parent(&self, cache: &Cache, item: &Walked) -> Result<()> {
+        match item.parent {
+            Some(ref parent) => cache.create_or_update_item(&CacheItem { path: parent.clone(), state: EmbeddingState::None }),
+            None => Ok(())
+        }
+    }
+}
+
+/// Sends the path and everything under it to the extraction workers, several directories being read at the same time.
+/// rules are the ignore rules of the parent of path
 #[async_recursion]
-async fn step(level: EmbeddingState, embedder: &Embedder, annoy: Arc<Mutex<Rannoy>>, path: PathBuf, recursion_level: usize) {
-    //println!("{} scanning {}", recursion_level, path.display());
-    let path = match path.canonicalize() {
-        Ok(p) => p,
-        Err(_) => return
-    };
-    let task = Task::new(CacheItem { path: path.clone(), state: EmbeddingState::None }, 0.);
-    let prompts = match embedder.get_prompts(&task).await {
-        Ok(ps) => ps,
-        Err(_) => return
-    };
-    let cache = embedder.cache.lock().await;
-    cache.create_or_update_item(&CacheItem { path: path.clone(), state: level });
-    let id = cache.get_id_by_path(&path).expect("Can't get id of item just created");
-    println!("{} {}", id, path.display());
-    if id == 0 {
-        println!("Id 0 for path {}", path.display());
+async fn walk(path: PathBuf, parent: Option<PathBuf>, depth: usize, rules: Arc<Ignore>, build: Arc<Build>, paths: mpsc::Sender<Walked>, permits: Arc<Semaphore>) -> Result<()> {
+    let is_dir = path.is_dir();
+    // Directories reached again through a symlink aren't sent twice
+    if is_dir && !build.filter.accepts_directory(&path, depth) {
+        return Ok(());
     }
-    drop(cache);
+    let walks_children = is_dir && !build.filter.is_too_deep(depth);
+    build.progress.add_found(1);
+    // The receivers are gone if the build stopped
+    if paths.send(Walked { path: path.clone(), parent, is_file: !is_dir, aggregated: walks_children }).await.is_err() || !walks_children {
+        return Ok(());
+    }
+    let permit = permits.acquire().await.expect("The walker semaphore is never closed");
+    let dir = path.clone();
+    let dir_build = build.clone();
+    // (path, is a directory), the ignore files of the directory are read with it
+    let (children, rules) = spawn_blocking(move || {
+        let rules = rules.child(&dir);
+        let children = match read_dir(&dir) {
+            Ok(entries) => entries.flatten()
+                .map(|entry| entry.path())
+                .filter(|child| dir_build.filter.accepts(child, child.is_dir(), &rules))
+                .filter_map(|child| child.canonicalize().ok())
+                .map(|child| { let is_dir = child.is_dir(); (child, is_dir) })
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new()
+        };
+        (children, rules)
+    }).await.expect("Directory reading panicked");
+    drop(permit);
+    build.directories.lock()?.insert(path.clone(), (depth, children.iter().map(|(child, _)| child.clone()).collect()));
+
+    // The files of a directory recorded by the interrupted build are already embedded, its subdirectories may not be
+    let sends_files = !build.processed_directories.contains(&path);
+    if sends_files {
+        build.progress.expect_files(&path, children.iter().filter(|(_, is_dir)| !is_dir).count());
+    }
+    let mut walkers = Vec::new();
+    for (child, is_dir) in children {
+        if is_dir {
+            walkers.push(tokio::spawn(walk(child, Some(path.clone()), depth + 1, rules.clone(), build.clone(), paths.clone(), permits.clone())));
+        } else if sends_files {
+            build.progress.add_found(1);
+            if paths.send(Walked { path: child, parent: Some(path.clone()), is_file: true, aggregated: false }).await.is_err() {
+                break;
+            }
+        }
+    }
+    for walker in walkers {
+        walker.await.expect("Walker panicked")?;
+    }
+    Ok(())
+}
+
+/// Reads the items received and sends their prompts to the model, the items that can keep or reuse vectors aren't sent.
+/// Returns the items given the vectors of an other item
+async fn extract(build: Arc<Build>, paths: Arc<Mutex<mpsc::Receiver<Walked>>>, extracted: mpsc::Sender<Extracted>) -> Result<HashSet<PathBuf>> {
+    let embedder = &build.embedder;
+    let level = build.level;
+    let mut reused = HashSet::new();
+    loop {
+        let item = match paths.lock().await.recv().await {
+            Some(item) => item,
+            None => return Ok(reused)
+        };
+        let path = item.path.clone();
+        embedder.invalidate_if_stale(&path).await?;
+        let cache = embedder.cache.lock().await;
+        // Items unchanged since they were embedded at this level keep their vectors, the directories whose children changed are embedded again at the end
+        if cache.contains(&CacheItem { path: path.clone(), state: level })? {
+            build.item_processed(&cache, &item)?;
+            continue;
+        }
+        drop(cache);
+        // Moved files and copies like vendored dependencies and backups reuse the vectors already computed
+        if embedder.embed_from_existing(&CacheItem { path: path.clone(), state: level }).await?.is_some() {
+            let cache = embedder.cache.lock().await;
+            build.outdate_parent(&cache, &item)?;
+            build.item_processed(&cache, &item)?;
+            reused.insert(path);
+            continue;
+        }
+        let prompts = match embedder.get_prompts(&Task::new(CacheItem { path: path.clone(), state: level }, 0.)).await {
+            Ok(prompts) => prompts,
+            Err(_) => {
+                build.item_processed(&*embedder.cache.lock().await, &item)?;
+                continue;
+            }
+        };
+        let cache = embedder.cache.lock().await;
+        // It isn't marked as embedded until its vectors are stored, so that an interrupted build embeds it again
+        cache.create_or_update_item(&CacheItem { path: path.clone(), state: EmbeddingState::None })?;
+        let id = cache.get_id_by_path(&path)?.ok_or_else(|| Error::ItemNotFound(path.clone()))?;
+        drop(cache);
+        match embedder.update_file_attributes(&path).await {
+            // Removed since its prompts were read, prune_under marks it as deleted at the end
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {},
+            result => result?
+        }
+        if extracted.send(Extracted { id, item, prompts }).await.is_err() {
+            return Ok(reused);
+        }
+    }
+}
+
+/// Embeds the prompts of several items in each call to the model, and returns the items embedded.
+/// The index is saved regularly, so that an interrupted build leaves the vectors already computed searchable
+async fn embed_batches(build: Arc<Build>, mut extracted: mpsc::Receiver<Extracted>) -> Result<HashSet<PathBuf>> {
+    let embedder = &build.embedder;
+    let mut embedded = HashSet::new();
+    let mut last_checkpoint = Instant::now();
+    while let Some(item) = extracted.recv().await {
+        let mut prompt_count = item.prompts.len();
+        let mut batch = vec![item];
+        // Only the items already waiting are added, the model doesn't wait for a full batch
+        while prompt_count < BATCH_SIZE {
+            match extracted.try_recv() {
+                Ok(item) => {
+                    prompt_count += item.prompts.len();
+                    batch.push(item);
+                },
+                Err(_) => break
+            }
+        }
+        let prompts = batch.iter().flat_map(|item| item.prompts.iter()).collect::<Vec<_>>();
+        let mut embeds = if prompts.len() > 0 {
+            embedder.embed(&prompts).await
+        } else {
+            Vec::new()
+        }.into_iter();
+        build.progress.add_chunks(prompts.len());
+
+        let mut cache = embedder.cache.lock().await;
+        for Extracted { id, item, prompts } in batch {
+            // The item is entirely embedded again, so its old vectors are replaced
+            cache.clear_embeds(id)?;
+            for (embed, prompt) in embeds.by_ref().take(prompts.len()).zip(prompts.iter()) {
+                cache.store_embed(&embed, id, prompt.range, Some(prompt.excerpt()))?;
+            }
+            if !item.aggregated {
+                cache.create_or_update_item(&CacheItem { path: item.path.clone(), state: build.level })?;
+            }
+            build.outdate_parent(&cache, &item)?;
+            build.item_processed(&cache, &item)?;
+            embedded.insert(item.path);
+        }
+        if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+            cache.save()?;
+            last_checkpoint = Instant::now();
+        }
+    }
+    Ok(embedded)
+}
+
+/// Embeds the prompts of an item already in the cache again, replacing its vectors, and returns false if its prompts can't be read
+async fn embed_again(embedder: &Embedder, level: EmbeddingState, path: &PathBuf) -> Result<bool> {
+    let prompts = match embedder.get_prompts(&Task::new(CacheItem { path: path.clone(), state: level }, 0.)).await {
+        Ok(prompts) => prompts,
+        Err(_) => return Ok(false)
+    };
     let embeds = if prompts.len() > 0 {
         embedder.embed(&prompts).await
     } else {
         Vec::new()
     };
-    for embed in embeds {
-        annoy.lock().await.add_item(id, embed.as_ref());
+    let mut cache = embedder.cache.lock().await;
+    let id = match cache.get_id_by_path(path)? {
+        Some(id) => id,
+        None => return Ok(false)
+    };
+    cache.clear_embeds(id)?;
+    for (embed, prompt) in embeds.iter().zip(prompts.iter()) {
+        cache.store_embed(embed, id, prompt.range, Some(prompt.excerpt()))?;
+    }
+    Ok(true)
+}
+
+/// Returns true if the vectors of a directory don't describe it anymore, because one of its children was given new vectors or deleted.
+/// The deleted children aren't walked, so they are found with changed_parents, the parents of the items in changed
+fn is_outdated(path: &Path, children: &[PathBuf], changed: &HashSet<PathBuf>, changed_parents: &HashSet<PathBuf>) -> bool {
+    changed_parents.contains(path) || children.iter().any(|child| changed.contains(child))
+}
+
+/// Also represents the directories by the mean of the vectors of their children, the deepest first so that their children are complete.
+/// changed contains the items given new vectors or deleted during the build, the directories unchanged whose children didn't change keep their vectors
+async fn aggregate_directories(build: &Build, directories: HashMap<PathBuf, (usize, Vec<PathBuf>)>, mut changed: HashSet<PathBuf>) -> Result<()> {
+    let embedder = &build.embedder;
+    let changed_parents = changed.iter().filter_map(|path| path.parent()).map(Path::to_path_buf).collect::<HashSet<_>>();
+    let mut directories = directories.into_iter().collect::<Vec<_>>();
+    directories.sort_by_key(|(_, (depth, _))| Reverse(*depth));
+    for (path, (_, children)) in directories {
+        if !changed.contains(&path) {
+            if !is_outdated(&path, &children, &changed, &changed_parents) {
+                continue;
+            }
+            // Its prompts describe its children and its README, and its old mean is replaced too
+            if !embed_again(embedder, build.level, &path).await? {
+                continue;
+            }
+        }
+        let mut cache = embedder.cache.lock().await;
+        let id = match cache.get_id_by_path(&path)? {
+            Some(id) => id,
+            None => continue
+        };
+        // (weight, mean vector of the child)
+        let mut children_embeds = Vec::new();
+        for child in children {
+            let weight = if is_readme(&child) { README_WEIGHT } else { 1. };
+            let child_embeds = match cache.get_id_by_path(&child)? {
+                Some(id) => cache.get_embeds(id)?,
+                None => continue
+            };
+            let child_embeds = child_embeds.into_iter().map(|e| (1., e)).collect::<Vec<_>>();
+            if let Some(mean) = weighted_mean(&child_embeds) {
+                children_embeds.push((weight, mean));
+            }
+        }
+        if let Some(mean) = weighted_mean(&children_embeds) {
+            cache.store_embed(&mean, id, None, None)?;
+        }
+        cache.create_or_update_item(&CacheItem { path: path.clone(), state: build.level })?;
+        drop(cache);
+        changed.insert(path);
+    }
+    Ok(())
+}
+
+/// Embeds the target and everything under it. If resume, the files of the directories recorded by an interrupted build of the target aren't checked again.
+/// The items embedded by a previous build and ignored since keep their vectors, only the deleted items are removed
+pub async fn build(target: &str, level: EmbeddingState, cache_path: &str, db_path: String, backend: IndexBackend, walk_options: WalkOptions, resume: bool) -> Result<()> {
+    let target = PathBuf::from(target).canonicalize()?;
+
+    let embedder = Embedder::new(Some(db_path), Some(cache_path.to_string()), backend).await?;
+    let mut cache = embedder.cache.lock().await;
+    cache.lock_for_writing()?;
+    cache.add_root(&target);
+    let processed_directories = if resume {
+        cache.get_processed_directories(&target, level)?
+    } else {
+        cache.clear_processed_directories(&target)?;
+        HashSet::new()
+    };
+    drop(cache);
+
+    if processed_directories.is_empty() {
+        println!("Starting the scan of {}", target.display());
+    } else {
+        println!("Resuming the scan of {}, {} directories already processed", target.display(), processed_directories.len());
     }
-    if let Ok(childs) = read_dir(path) {
-        for child in childs {
-            if let Ok(child) = child {
-                step(level, embedder, annoy.clone(), child.path(), recursion_level+1).await;
+
+    // walker -> extraction workers -> model, the bounded channels make the faster stages wait for the slower ones
+    let (paths_sender, paths) = mpsc::channel(CHANNEL_CAPACITY);
+    let (extracted_sender, extracted) = mpsc::channel(CHANNEL_CAPACITY);
+    let build = Arc::new(Build {
+        embedder: embedder.clone(),
+        level,
+        root: target.clone(),
+        filter: WalkFilter::new(&target, walk_options),
+        directories: Directories::default(),
+        processed_directories,
+        progress: Progress::new()
+    });
+    // Only the children are checked, the target is embedded even if it's ignored as it was asked for
+    let rules = Ignore::for_dir(target.parent().unwrap_or(&target), walk_options.respect_ignore);
+    let walker = tokio::spawn(walk(target.clone(), None, 0, rules, build.clone(), paths_sender, Arc::new(Semaphore::new(WALK_CONCURRENCY))));
+    let paths = Arc::new(Mutex::new(paths));
+    let extractors = (0..EXTRACTION_WORKERS)
+        .map(|_| tokio::spawn(extract(build.clone(), paths.clone(), extracted_sender.clone())))
+        .collect::<Vec<_>>();
+    drop(extracted_sender);
+    let batcher = tokio::spawn(embed_batches(build.clone(), extracted));
+    let display = tokio::spawn({
+        let build = build.clone();
+        async move {
+            loop {
+                build.progress.display();
+                tokio::time::sleep(PROGRESS_INTERVAL).await;
             }
         }
+    });
+
+    let mut changed = batcher.await.expect("Embedding task panicked")?;
+    for extractor in extractors {
+        changed.extend(extractor.await.expect("Extraction worker panicked")?);
     }
+    walker.await.expect("Walker panicked")?;
+    display.abort();
+    build.progress.finish();
+
+    // The directories containing deleted items changed too, their prompts and their mean still describe the deleted items
+    let deleted = embedder.cache.lock().await.prune_under(&target)?;
+    let deleted_count = deleted.len();
+    changed.extend(deleted);
+    let directories = std::mem::take(&mut *build.directories.lock()?);
+    aggregate_directories(&build, directories, changed).await?;
+    let mut cache = embedder.cache.lock().await;
+    cache.save()?;
+    // The build is complete, the next one starts again from the target
+    cache.clear_processed_directories(&target)?;
+
+    println!("Done! {} vectors indexed, {} deleted items removed", cache.len(), deleted_count);
+    Ok(())
 }
 
-pub async fn build(target: &str, level: EmbeddingState, cache_path: &str, db_path: String) {
-    let target = PathBuf::from(target);
+/// Removes from the index the items whose path doesn't exist anymore.
+/// The items ignored since they were embedded still exist, so they stay in the index
+pub fn prune(cache_path: &str, db_path: String, backend: IndexBackend) -> Result<()> {
+    let mut cache = Cache::new(Some(db_path), Some(cache_path.to_string()), backend, false)?;
+    cache.lock_for_writing()?;
+
+    let (deleted, removed) = cache.prune()?;
+    cache.save()?;
+
+    println!("Done! {} deleted items found, {} old deleted items removed", deleted, removed);
+    Ok(())
+}
 
-    let embedder = Embedder::new(Some(db_path), None).await;
+/// Rebuilds the index file from the vectors stored in the DB, without running the model
+pub fn reindex(cache_path: &str, db_path: String, backend: IndexBackend) -> Result<()> {
+    let mut cache = Cache::new(Some(db_path), Some(cache_path.to_string()), backend, true)?;
+    cache.lock_for_writing()?;
 
-    let annoy = Arc::new(Mutex::new(Rannoy::new(384)));
-    annoy.lock().await.set_seed(123); // 123 is the seed for the random number generator
+    let count = cache.rebuild_index()?;
 
-    println!("Starting the scan of {}", target.display());
+    println!("Done! {} vectors indexed", count);
+    Ok(())
+}
 
-    step(level, &embedder, annoy.clone(), target, 0).await;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let annoy = annoy.lock().await;
-    annoy.build(30); // 30 is the number of trees (higher = more precision)
+    #[test]
+    fn prune_under_returns_the_deleted_file_and_outdates_its_directory() {
+        let root = std::env::temp_dir().join(format!("search-rust-test-{}-deleted", std::process::id()));
+        let directory = root.join("directory");
+        let other = root.join("other");
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::create_dir_all(&other).unwrap();
+        let kept = directory.join("kept.txt");
+        let deleted = directory.join("deleted.txt");
+        let untouched = other.join("untouched.txt");
+        for path in [&kept, &deleted, &untouched] {
+            std::fs::write(path, "content").unwrap();
+        }
+        let mut cache = Cache::new(None, None, IndexBackend::BruteForce, false).unwrap();
+        for path in [&directory, &other, &kept, &deleted, &untouched] {
+            cache.create_or_update_item(&CacheItem { path: path.clone(), state: EmbeddingState::Name }).unwrap();
+        }
 
-    annoy.save(cache_path);
+        std::fs::remove_file(&deleted).unwrap();
+        let pruned = cache.prune_under(&root).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(pruned, vec![deleted]);
 
-    println!("Done!");
-}
\ No newline at end of file
+        let changed = pruned.into_iter().collect::<HashSet<_>>();
+        let changed_parents = changed.iter().filter_map(|path| path.parent()).map(Path::to_path_buf).collect::<HashSet<_>>();
+        assert!(is_outdated(&directory, &[kept], &changed, &changed_parents));
+        assert!(!is_outdated(&other, &[untouched], &changed, &changed_parents));
+    }
+}
@@ -0,0 +1,169 @@
+/// Dry run of a build: what it would embed and what it would cost, without writing anything
+
+use std::{path::PathBuf, fs::{read_dir, metadata, File}, io::Read, sync::Arc, collections::HashMap, time::{Duration, Instant}};
+use crate::embedding::{Embedder, EmbeddingState, Task, CacheItem, IndexBackend, Prompt, is_document};
+use crate::error::Result;
+use crate::ignore::Ignore;
+use crate::walk::{WalkFilter, WalkOptions};
+use tokio::task::spawn_blocking;
+use super::{BATCH_SIZE, progress::format_duration};
+
+/// Number of bytes read at the start of a file to decide if it's text
+const SNIFF_LENGTH: usize = 8192;
+/// Average size of a paragraph, to estimate the chunks of the files that aren't read
+const PARAGRAPH_LENGTH: u64 = 500;
+
+/// Files of a type found by the dry run
+#[derive(Default)]
+struct TypeStats {
+    files: usize,
+    bytes: u64
+}
+
+/// Items under path visited by the build, with whether they are directories. rules are the ignore rules of the parent of path
+fn collect(path: PathBuf, depth: usize, rules: &Arc<Ignore>, filter: &WalkFilter, items: &mut Vec<(PathBuf, bool)>) {
+    let is_dir = path.is_dir();
+    if is_dir && !filter.accepts_directory(&path, depth) {
+        return;
+    }
+    items.push((path.clone(), is_dir));
+    if !is_dir || filter.is_too_deep(depth) {
+        return;
+    }
+    let rules = rules.child(&path);
+    let entries = match read_dir(&path) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for child in entries.flatten().map(|entry| entry.path()) {
+        if !filter.accepts(&child, child.is_dir(), &rules) {
+            continue;
+        }
+        if let Ok(child) = child.canonicalize() {
+            collect(child, depth + 1, &rules, filter, items);
+        }
+    }
+}
+
+/// Returns true if the start of the file is UTF-8 without NUL bytes, the content of the other files isn't embedded
+fn is_text(path: &PathBuf) -> bool {
+    let mut start = Vec::with_capacity(SNIFF_LENGTH);
+    if File::open(path).and_then(|file| file.take(SNIFF_LENGTH as u64).read_to_end(&mut start)).is_err() {
+        return false;
+    }
+    // The last character can be cut
+    let valid = match std::str::from_utf8(&start) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none()
+    };
+    valid && !start.contains(&0)
+}
+
+/// Size of the text the build would extract from a file of size bytes
+async fn extractable_bytes(path: &PathBuf, size: u64) -> u64 {
+    if is_document(path) {
+        return Embedder::read_file_content(path).await.map_or(0, |content| content.len() as u64);
+    }
+    let sniffed = path.clone();
+    match spawn_blocking(move || is_text(&sniffed)).await {
+        Ok(true) => size,
+        _ => 0
+    }
+}
+
+/// Measures the speed of the model on prompts of the target, in chunks per second. The first call loads the model so it isn't timed
+async fn benchmark(embedder: &Embedder, samples: &[Prompt]) -> f64 {
+    embedder.embed(&samples[..1]).await;
+    let start = Instant::now();
+    embedder.embed(samples).await;
+    samples.len() as f64 / start.elapsed().as_secs_f64().max(0.001)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Walks the target with the rules of the build and reports what the build would do at level.
+/// The prompts of the names and of the metadata are extracted, the chunks of the contents are estimated from their size after the first files.
+/// The cache is in memory and has no index file, so nothing is written
+pub async fn dry_run(target: &str, level: EmbeddingState, backend: IndexBackend, walk_options: WalkOptions) -> Result<()> {
+    let target = PathBuf::from(target).canonicalize()?;
+    println!("Dry run of {} at level {:?}, nothing is written", target.display(), level);
+
+    let walk_target = target.clone();
+    let items = spawn_blocking(move || {
+        let rules = Ignore::for_dir(walk_target.parent().unwrap_or(&walk_target), walk_options.respect_ignore);
+        let filter = WalkFilter::new(&walk_target, walk_options);
+        let mut items = Vec::new();
+        collect(walk_target, 0, &rules, &filter, &mut items);
+        items
+    }).await.expect("Walker panicked");
+
+    let embedder = Embedder::new(None, None, backend).await?;
+    let mut types: HashMap<String, TypeStats> = HashMap::new();
+    let mut directories = 0;
+    let mut extractable = 0;
+    let mut chunks = 0;
+    // Prompts of the first files, to measure the model on texts like the ones of the build
+    let mut samples = Vec::new();
+    for (path, is_dir) in items {
+        let mut text_bytes = 0;
+        if is_dir {
+            directories += 1;
+        } else {
+            let size = metadata(&path).map_or(0, |m| m.len());
+            let kind = path.extension().map_or("(none)".to_string(), |e| e.to_string_lossy().to_lowercase());
+            let stats = types.entry(kind).or_default();
+            stats.files += 1;
+            stats.bytes += size;
+            text_bytes = extractable_bytes(&path, size).await;
+            extractable += text_bytes;
+        }
+        if let (EmbeddingState::Paragraphs(nb), false) = (level, is_dir) {
+            if text_bytes == 0 {
+                continue;
+            }
+            if samples.len() >= BATCH_SIZE {
+                chunks += (nb as u64).min(text_bytes.div_ceil(PARAGRAPH_LENGTH)) as usize;
+                continue;
+            }
+        }
+        let prompts = embedder.get_prompts(&Task::new(CacheItem { path, state: level }, 0.)).await.unwrap_or_default();
+        chunks += prompts.len();
+        if !is_dir && samples.len() < BATCH_SIZE {
+            samples.extend(prompts);
+        }
+    }
+    samples.truncate(BATCH_SIZE);
+    let files = types.values().map(|stats| stats.files).sum::<usize>();
+    // The directories whose children are walked also get the mean of their children
+    let means = if level > EmbeddingState::None { directories } else { 0 };
+
+    println!("{} files and {} directories", files, directories);
+    let mut types = types.into_iter().collect::<Vec<_>>();
+    types.sort_by(|(a_kind, a), (b_kind, b)| b.files.cmp(&a.files).then(a_kind.cmp(b_kind)));
+    for (kind, stats) in types {
+        println!("  {:<12} {:>8} files {:>12}", kind, stats.files, format_bytes(stats.bytes));
+    }
+    println!("Extractable text: {}", format_bytes(extractable));
+    println!("Estimated chunks: {}, and {} directory means", chunks, means);
+    println!("Expected index size ({}): {}", backend.name(), format_bytes(backend.estimated_size(chunks + means)));
+    if samples.is_empty() {
+        println!("No file to embed, the model isn't benchmarked");
+    } else {
+        let rate = benchmark(&embedder, &samples).await;
+        println!("Model speed: {:.1} chunks/s, estimated embedding time {}", rate, format_duration(Duration::from_secs_f64(chunks as f64 / rate)));
+    }
+    Ok(())
+}
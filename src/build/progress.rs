@@ -0,0 +1,85 @@
+/// Counters of a build, shown on a line rewritten in place
+
+use std::{path::{Path, PathBuf}, collections::HashMap, io::stdout, sync::{Mutex, atomic::{AtomicUsize, Ordering}}, time::{Duration, Instant}};
+use crossterm::{terminal::{self, ClearType}, execute, cursor, style::Print};
+
+pub struct Progress {
+    start: Instant,
+    /// Items sent by the walker
+    found: AtomicUsize,
+    /// Items kept, reused or embedded
+    processed: AtomicUsize,
+    /// Prompts given to the model
+    chunks: AtomicUsize,
+    /// Number of files of each directory not processed yet
+    pending: Mutex<HashMap<PathBuf, usize>>
+}
+impl Progress {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            found: AtomicUsize::new(0),
+            processed: AtomicUsize::new(0),
+            chunks: AtomicUsize::new(0),
+            pending: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn add_found(&self, count: usize) {
+        self.found.fetch_add(count, Ordering::Relaxed);
+    }
+    pub fn add_chunks(&self, count: usize) {
+        self.chunks.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Registers the files of a directory, before they are sent
+    pub fn expect_files(&self, directory: &Path, count: usize) {
+        if count > 0 {
+            self.pending.lock().unwrap_or_else(|e| e.into_inner()).insert(directory.to_path_buf(), count);
+        }
+    }
+
+    /// Counts a processed item, a file of directory if is_file.
+    /// Returns the directory if it was its last file not processed yet
+    pub fn processed(&self, directory: Option<&Path>, is_file: bool) -> Option<PathBuf> {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        let directory = directory.filter(|_| is_file)?;
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let count = pending.get_mut(directory)?;
+        *count -= 1;
+        if *count > 0 {
+            return None;
+        }
+        pending.remove(directory);
+        Some(directory.to_path_buf())
+    }
+
+    /// Replaces the current line with the counters, the rates and the estimated remaining time.
+    /// The remaining time assumes the walker found everything, so it grows while the walk goes on
+    pub fn display(&self) {
+        let elapsed = self.start.elapsed().as_secs_f64().max(0.001);
+        let found = self.found.load(Ordering::Relaxed);
+        let processed = self.processed.load(Ordering::Relaxed);
+        let files_rate = processed as f64 / elapsed;
+        let chunks_rate = self.chunks.load(Ordering::Relaxed) as f64 / elapsed;
+        let eta = if files_rate > 0. {
+            format_duration(Duration::from_secs_f64(found.saturating_sub(processed) as f64 / files_rate))
+        } else {
+            "--:--:--".to_string()
+        };
+        let line = format!("{}/{} items, {:.1} files/s, {:.1} chunks/s, ETA {}", processed, found, files_rate, chunks_rate, eta);
+        // The progress isn't worth failing the build
+        let _ = execute!(stdout(), cursor::MoveToColumn(0), terminal::Clear(ClearType::CurrentLine), Print(line));
+    }
+
+    /// Displays the last counters and ends their line
+    pub fn finish(&self) {
+        self.display();
+        println!();
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
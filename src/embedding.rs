@@ -1,12 +1,137 @@
-use std::{path::PathBuf, sync::Arc, collections::BinaryHeap, io::Read};
+use std::{path::{PathBuf, Component}, sync::Arc, collections::BinaryHeap, io::Read, fs::read_dir};
 use crate::error::{Result, Error};
 use rust_bert::pipelines::sentence_embeddings::{builder::SentenceEmbeddingsBuilder, SentenceEmbeddingsModel, SentenceEmbeddingsModelType::AllMiniLmL12V2};
 use dotext::{self, MsDoc, doc::OpenOfficeDoc};
 use tokio::{sync::{Mutex, RwLock}, task::spawn_blocking};
 
 mod cache;
-use cache::{Cache, Id};
-pub use cache::{EmbeddingState, CacheItem};
+mod metadata;
+mod attributes;
+pub use cache::{Cache, EmbeddingState, CacheItem, IndexBackend, Chunk, ChunkAggregation, Id};
+
+/// Name of the model producing the vectors, stored with them so that vectors of different models are never mixed
+pub const MODEL_ID: &str = "all-MiniLM-L12-v2";
+pub use attributes::expand_relative_dates;
+
+/// Number of parent directories given as context in the name prompts
+const LOCATION_DEPTH: usize = 3;
+/// Maximum number of children names listed in the prompts of a directory
+const DIRECTORY_CHILDREN_PROMPT_COUNT: usize = 20;
+/// Extensions of the office documents whose text is extracted instead of being read as is
+const DOCUMENT_EXTENSIONS: [&str; 5] = ["docx", "xlsx", "pptx", "odt", "odp"];
+/// Wait before checking again the lock held by another process writing the cache, the tasks staying queued meanwhile
+const LOCKED_TASKS_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Scales a vector to a L2 norm of 1, so that the euclidean distance between two vectors gives their cosine similarity
+pub fn normalize(vector: &mut [f32; 384]) {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0. {
+        for v in vector.iter_mut() {
+            *v /= norm;
+        }
+    }
+}
+
+pub fn is_readme(path: &PathBuf) -> bool {
+    path.file_stem().and_then(|s| s.to_str()).map_or(false, |s| s.eq_ignore_ascii_case("readme"))
+}
+
+pub fn is_document(path: &PathBuf) -> bool {
+    path.extension().and_then(|e| e.to_str()).map_or(false, |e| DOCUMENT_EXTENSIONS.contains(&e))
+}
+
+/// Returns true for the words that carry no meaning in a name, like versions (`v2`) or dates (`2023`, `05`)
+fn is_name_noise(word: &str) -> bool {
+    let digits = word.strip_prefix(['v', 'V']).unwrap_or(word);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Splits an identifier like `parseHttpResponse`, `user-settings` or `v2.final.backup` into lowercase words and removes versions and dates
+fn split_identifier(identifier: &str) -> String {
+    let chars = identifier.chars().collect::<Vec<char>>();
+    let mut words: Vec<String> = Vec::new();
+    let mut word = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        if let Some(previous) = i.checked_sub(1).map(|i| chars[i]) {
+            let next = chars.get(i + 1);
+            // camelCase and HTTPResponse boundaries
+            let boundary = (c.is_uppercase() && (previous.is_lowercase() || previous.is_ascii_digit()))
+                || (c.is_uppercase() && previous.is_uppercase() && next.map_or(false, |n| n.is_lowercase()));
+            if boundary && !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+        word.extend(c.to_lowercase());
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    let meaningful = words.iter().filter(|w| !is_name_noise(w)).cloned().collect::<Vec<_>>();
+    if meaningful.is_empty() {
+        // Keep the noise rather than an empty name
+        words.join(" ")
+    } else {
+        meaningful.join(" ")
+    }
+}
+
+/// Maximum number of characters of the excerpt stored with a vector
+const EXCERPT_LENGTH: usize = 120;
+
+/// A text to embed and the part of the item it comes from
+#[derive(Debug, Clone)]
+pub struct Prompt {
+    pub text: String,
+    /// Byte range of the text in the content of the file, None if the text isn't taken from the content
+    pub range: Option<(usize, usize)>
+}
+impl Prompt {
+    /// Start of the text on a single line, to be displayed with the results
+    pub fn excerpt(&self) -> String {
+        let text = self.text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.chars().count() > EXCERPT_LENGTH {
+            text.chars().take(EXCERPT_LENGTH).collect::<String>() + "…"
+        } else {
+            text
+        }
+    }
+}
+impl From<String> for Prompt {
+    fn from(text: String) -> Self {
+        Self {
+            text,
+            range: None
+        }
+    }
+}
+impl AsRef<str> for Prompt {
+    fn as_ref(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Splits a content in paragraphs separated by empty lines and returns their byte ranges
+fn split_paragraphs(content: &str) -> Vec<(usize, usize)> {
+    let mut paragraphs = Vec::new();
+    let mut start = 0;
+    for (i, separator) in content.match_indices("\n\n") {
+        if i > start {
+            paragraphs.push((start, i));
+        }
+        start = i + separator.len();
+    }
+    if content.len() > start {
+        paragraphs.push((start, content.len()));
+    }
+    paragraphs
+}
 
 #[derive(Debug)]
 pub struct Task {
@@ -21,6 +146,9 @@ impl Task {
             priority
         }
     }
+    pub fn item(&self) -> &CacheItem {
+        &self.item
+    }
 }
 impl PartialEq for Task {
     fn eq(&self, other: &Self) -> bool {
@@ -50,25 +178,31 @@ pub struct Embedder {
     model_queue: Arc<Mutex<()>>,
     pub cache: Arc<Mutex<Cache>>,
     /// (path to embed, priority (lower is higher))
-    tasks: Arc<RwLock<BinaryHeap<Task>>>
+    tasks: Arc<RwLock<BinaryHeap<Task>>>,
+    /// Errors of the background tasks, given to the caller by check_tasks
+    task_errors: Arc<Mutex<Vec<Error>>>
 }
 impl Embedder {
-    pub async fn new(db_path: Option<String>, cache_path: Option<String>) -> Self {
+    pub async fn new(db_path: Option<String>, cache_path: Option<String>, backend: IndexBackend) -> Result<Self> {
+        let cache = Cache::new(db_path, cache_path, backend, false)?;
         let model = spawn_blocking(move || {
             SentenceEmbeddingsBuilder::remote(AllMiniLmL12V2).create_model().unwrap()
         }).await.expect("Can't create model");
-        Self {
+        Ok(Self {
             model: Arc::new(Mutex::new(model)),
             model_queue: Arc::new(Mutex::new(())),
-            cache: Arc::new(Mutex::new(Cache::new(db_path, cache_path))),
-            tasks: Arc::new(RwLock::new(BinaryHeap::new()))
-        }
+            cache: Arc::new(Mutex::new(cache)),
+            tasks: Arc::new(RwLock::new(BinaryHeap::new())),
+            task_errors: Arc::new(Mutex::new(Vec::new()))
+        })
     }
     pub async fn embed_high_priotity<S>(&self, sentences: &[S]) -> Vec<Arc<[f32; 384]>>
     where S: AsRef<str> + Sync {
         let embeds = self.model.lock().await.encode(sentences).expect("Can't embed with model");
         let embeds: Vec<Arc<[f32; 384]>> = embeds.into_iter().map(|embed|{
-            Arc::new(embed.as_slice().try_into().unwrap())
+            let mut embed: [f32; 384] = embed.as_slice().try_into().unwrap();
+            normalize(&mut embed);
+            Arc::new(embed)
         }).collect();
         embeds
     }
@@ -79,20 +213,19 @@ impl Embedder {
         drop(queue);
         embeds
     }
-    pub async fn add_sentences_to_id<S>(&self, sentences: &[S], id: Id)
-    where S: AsRef<str> + Sync {
-        let embeds = self.embed(sentences).await;
+    pub async fn add_prompts_to_id(&self, prompts: &[Prompt], id: Id) -> Result<()> {
+        let embeds = self.embed(prompts).await;
         let mut cache = self.cache.lock().await;
-        for embed in embeds {
-            cache.add_embed_to_id(embed, id);
+        for (embed, prompt) in embeds.into_iter().zip(prompts.iter()) {
+            cache.add_embed_to_id(embed, id, prompt.range, Some(prompt.excerpt()))?;
         }
+        Ok(())
     }
-    pub async fn add_sentences_to_path<S>(&self, sentences: &[S], path: &PathBuf)
-    where S: AsRef<str> + Sync {
+    pub async fn add_prompts_to_path(&self, prompts: &[Prompt], path: &PathBuf) -> Result<()> {
         let cache = self.cache.lock().await;
-        let id = cache.get_id_by_path(path).expect("Trying to get id of unknown path");
+        let id = cache.get_id_by_path(path)?.ok_or_else(|| Error::ItemNotFound(path.clone()))?;
         drop(cache);
-        self.add_sentences_to_id(sentences, id).await;
+        self.add_prompts_to_id(prompts, id).await
     }
 
     pub async fn read_file_content(path: &PathBuf) -> Result<String> {
@@ -137,40 +270,128 @@ impl Embedder {
         let mut prompts = Vec::new();
         let filename = match path.file_name() {
             None => return Ok(prompts),
-            Some(filename) => filename.to_str().ok_or(Error::CannotConvertOsStr)?
+            Some(filename) => filename.to_string_lossy()
         };
         if path.is_dir() {
-            prompts.push("directory: ".to_string() + filename);
+            prompts.push("directory: ".to_string() + &filename);
         } else {
-            prompts.push("file: ".to_string() + filename);
+            prompts.push("file: ".to_string() + &filename);
         }
 
-        let name = path.file_stem().ok_or(Error::CannotGetFileStem)?.to_str().ok_or(Error::CannotConvertOsStr)?.replace('_', " ");
+        let name = split_identifier(&path.file_stem().ok_or(Error::CannotGetFileStem)?.to_string_lossy());
         prompts.push("name: ".to_string() + &name);
 
         if !path.is_dir() {
             if let Some(e) = path.extension() {
-                let e = e.to_str().ok_or(Error::CannotConvertOsStr)?;
-                prompts.push("extension: ".to_string() + e);
+                prompts.push("extension: ".to_string() + &e.to_string_lossy());
+            }
+        }
+
+        // Give the directory context, so that a file can be found by its location before its content is indexed
+        if let Some(parent) = path.parent() {
+            let mut location = parent.components().rev()
+                .filter_map(|c| match c {
+                    Component::Normal(c) => Some(c.to_string_lossy()),
+                    _ => None
+                })
+                .take(LOCATION_DEPTH)
+                .map(|c| split_identifier(&c))
+                .filter(|c| !c.is_empty())
+                .collect::<Vec<_>>();
+            if !location.is_empty() {
+                location.reverse();
+                prompts.push("located in: ".to_string() + &location.join(" > "));
             }
         }
 
         Ok(prompts)
     }
 
-    async fn get_file_content_prompts(&self, path: &PathBuf) -> Result<Vec<String>> {
+    /// Describes a directory by the names of its children and the first paragraph of its README
+    async fn get_directory_prompts(&self, path: &PathBuf) -> Result<Vec<String>> {
+        let mut prompts = Vec::new();
+        let mut names = Vec::new();
+        let mut readme = None;
+        for child in read_dir(path)?.flatten() {
+            let child = child.path();
+            if is_readme(&child) && child.is_file() {
+                readme = Some(child.clone());
+            }
+            if let Some(stem) = child.file_stem().and_then(|s| s.to_str()) {
+                names.push(split_identifier(stem));
+            }
+        }
+        names.retain(|n| !n.is_empty());
+        names.sort();
+        names.dedup();
+        if !names.is_empty() {
+            names.truncate(DIRECTORY_CHILDREN_PROMPT_COUNT);
+            prompts.push("contains: ".to_string() + &names.join(", "));
+        }
+        if let Some(readme) = readme {
+            if let Ok(content) = Self::read_file_content(&readme).await {
+                if let Some(p) = content.split("\n\n").map(|p| p.trim()).find(|p| !p.is_empty()) {
+                    prompts.push("readme: ".to_string() + p);
+                }
+            }
+        }
+        Ok(prompts)
+    }
+
+    /// Removes the vectors of an item changed since it was embedded, and returns true if it did.
+    /// The file is hashed without holding the cache, the lock is only taken to compare and update the DB
+    pub async fn invalidate_if_stale(&self, path: &PathBuf) -> Result<bool> {
+        let content_hash = if self.cache.lock().await.needs_content_hash(path)? {
+            let hash_path = path.clone();
+            spawn_blocking(move || attributes::content_hash(&hash_path)).await.ok().and_then(|hash| hash.ok()).flatten()
+        } else {
+            None
+        };
+        self.cache.lock().await.invalidate_if_stale(path, content_hash)
+    }
+
+    /// Stores the filesystem metadata and the content hash of an item already present in the cache
+    pub async fn update_file_attributes(&self, path: &PathBuf) -> Result<()> {
+        let attributes = attributes::get_file_attributes(path)?;
+        let hash_path = path.clone();
+        let content_hash = spawn_blocking(move || attributes::content_hash(&hash_path)).await.ok().and_then(|hash| hash.ok()).flatten();
+        let cache = self.cache.lock().await;
+        cache.set_attributes(path, &attributes)?;
+        cache.set_content_hash(path, content_hash)
+    }
+
+    fn get_file_attributes_prompts(&self, path: &PathBuf) -> Result<Vec<String>> {
+        let attributes = attributes::get_file_attributes(path)?;
+        Ok(attributes::get_attributes_prompts(&attributes, path.is_dir()))
+    }
+
+    async fn get_file_metadata_prompts(&self, path: &PathBuf) -> Result<Vec<String>> {
+        let mut prompts = self.get_file_name_prompts(path)?;
+        if let Ok(attributes_prompts) = self.get_file_attributes_prompts(path) {
+            prompts.extend(attributes_prompts);
+        }
+        if !path.is_dir() {
+            let path = path.clone();
+            if let Ok(Ok(metadata_prompts)) = spawn_blocking(move || metadata::get_media_metadata_prompts(&path)).await {
+                prompts.extend(metadata_prompts);
+            }
+        }
+        Ok(prompts)
+    }
+
+    async fn get_file_content_prompts(&self, path: &PathBuf) -> Result<Vec<Prompt>> {
         let mut prompts = Vec::new();
         if !path.is_dir() {
             if let Ok(content) = Self::read_file_content(&path).await {
                 if !content.is_empty() {
-                    prompts.push(content);
+                    prompts.push(Prompt { range: Some((0, content.len())), text: content });
                 }
             }
         }
         Ok(prompts)
     }
 
-    async fn get_file_paragraphs_prompts(&self, path: &PathBuf, nb: usize) -> Result<Vec<String>> {
+    async fn get_file_paragraphs_prompts(&self, path: &PathBuf, nb: usize) -> Result<Vec<Prompt>> {
         if nb == 1 {
             return self.get_file_content_prompts(path).await;
         }
@@ -178,24 +399,21 @@ impl Embedder {
         if !path.is_dir() {
             if let Ok(content) = Self::read_file_content(&path).await {
                 if !content.is_empty() {
-                    let mut content: Vec<String> = content.split("\n\n").map(|p| p.to_string()).collect();
-
-                    // Remove empty paragraphs
-                    content.retain(|p| !p.is_empty());
+                    let mut paragraphs = split_paragraphs(&content);
 
                     // if there is more paragraphs than nb, group them 2 by 2 until there is nb paragraphs
-                    if content.len() > nb {
-                        while content.len() > nb {
-                            let mut new_content: Vec<String> = Vec::new();
-                            for double in content.chunks(2) {
-                                new_content.push(double.join("\n\n"));
+                    if paragraphs.len() > nb {
+                        while paragraphs.len() > nb {
+                            let mut new_paragraphs = Vec::new();
+                            for double in paragraphs.chunks(2) {
+                                new_paragraphs.push((double[0].0, double[double.len()-1].1));
                             }
-                            content = new_content;
+                            paragraphs = new_paragraphs;
                         }
                     }
 
-                    for p in content {
-                        prompts.push(p.to_string());
+                    for (start, end) in paragraphs {
+                        prompts.push(Prompt { text: content[start..end].to_string(), range: Some((start, end)) });
                     }
                 }
             }
@@ -203,41 +421,130 @@ impl Embedder {
         Ok(prompts)
     }
 
-    pub async fn get_prompts(&self, task: &Task) -> std::result::Result<Vec<String>, Error> {
-        let prompts = match task.item.state {
+    pub async fn get_prompts(&self, task: &Task) -> std::result::Result<Vec<Prompt>, Error> {
+        let texts_to_prompts = |texts: Vec<String>| texts.into_iter().map(Prompt::from).collect::<Vec<_>>();
+        let mut prompts = match task.item.state {
             EmbeddingState::None => Ok(Vec::new()),
-            EmbeddingState::Name => self.get_file_name_prompts(&task.item.path),
+            EmbeddingState::Name => self.get_file_name_prompts(&task.item.path).map(texts_to_prompts),
+            EmbeddingState::Metadata => self.get_file_metadata_prompts(&task.item.path).await.map(texts_to_prompts),
             EmbeddingState::Paragraphs(nb) => self.get_file_paragraphs_prompts(&task.item.path, nb).await,
             _ => Err(Error::NotImplementedYet)
         };
+        if let Ok(ref mut prompts) = prompts {
+            if task.item.state >= EmbeddingState::Name && task.item.path.is_dir() {
+                if let Ok(directory_prompts) = self.get_directory_prompts(&task.item.path).await {
+                    prompts.extend(texts_to_prompts(directory_prompts));
+                }
+            }
+        }
         prompts
     }
 
-    pub async fn execute_task(&self, task: Task) {
-        if self.cache.lock().await.contains(&task.item) {
-            return;
+    /// Embeds the content of a file without the model, with the vectors of the same file deleted from its old path or of an identical file.
+    /// Returns the id of the item if there was one
+    pub async fn embed_from_existing(&self, item: &CacheItem) -> Result<Option<Id>> {
+        if !matches!(item.state, EmbeddingState::Paragraphs(_)) || item.path.is_dir() {
+            return Ok(None);
         }
+        let path = item.path.clone();
+        let content_hash = match spawn_blocking(move || attributes::content_hash(&path)).await {
+            Ok(Ok(Some(content_hash))) => content_hash,
+            _ => return Ok(None)
+        };
+        let attributes = match attributes::get_file_attributes(&item.path) {
+            Ok(attributes) => attributes,
+            Err(_) => return Ok(None)
+        };
 
-        let prompts = self.get_prompts(&task).await;
+        let mut cache = self.cache.lock().await;
+        if let Some(id) = cache.move_deleted_item(&item.path, item.state, &attributes, Some(content_hash))? {
+            return Ok(Some(id));
+        }
+        let duplicate = match cache.find_duplicate(content_hash, &item.path, item.state)? {
+            Some(duplicate) => duplicate,
+            None => return Ok(None)
+        };
+        cache.create_or_update_item(item)?;
+        let id = cache.get_id_by_path(&item.path)?.ok_or_else(|| Error::ItemNotFound(item.path.clone()))?;
+        cache.set_attributes(&item.path, &attributes)?;
+        cache.set_content_hash(&item.path, Some(content_hash))?;
+        cache.copy_content_embeds(duplicate, id)?;
+        cache.save_if_needed()?;
+        Ok(Some(id))
+    }
+
+    /// The caller holds the write lock, taken with begin_write
+    async fn execute_locked_task(&self, task: &Task) -> Result<()> {
+        // The vectors of an item changed since it was embedded don't describe it anymore
+        self.invalidate_if_stale(&task.item.path).await?;
+        let cache = self.cache.lock().await;
+        if cache.contains(&task.item)? {
+            return Ok(());
+        }
+        drop(cache);
+
+        if self.embed_from_existing(&task.item).await?.is_some() {
+            return Ok(());
+        }
+
+        let prompts = self.get_prompts(task).await;
 
         if let Ok(prompts) = prompts {
             if prompts.len() > 0 {
-                self.cache.lock().await.create_or_update_item(&task.item);
-                self.add_sentences_to_path(&prompts, &task.item.path).await;
+                self.cache.lock().await.create_or_update_item(&task.item)?;
+                match self.update_file_attributes(&task.item.path).await {
+                    // Removed since its prompts were read, it will be marked as deleted by the next prune
+                    Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {},
+                    result => result?
+                }
+                self.add_prompts_to_path(&prompts, &task.item.path).await?;
+                self.cache.lock().await.save_if_needed()?;
             }
         }
+        Ok(())
+    }
+
+    /// Returns the first error of the background tasks since the last call
+    pub async fn check_tasks(&self) -> Result<()> {
+        match self.task_errors.lock().await.drain(..).next() {
+            Some(e) => Err(e),
+            None => Ok(())
+        }
     }
 
     pub fn execute_tasks(&self) -> Result<()> {
         let clone = self.clone();
         tokio::spawn(async move {
             loop {
-                if let Some(task) = clone.tasks.write().await.pop() {
-                    let clone = clone.clone();
-                    tokio::spawn(async move {
-                        clone.execute_task(task).await;
-                    });
+                if clone.tasks.read().await.is_empty() {
+                    continue;
                 }
+                // While another process writes the cache, the tasks are paused instead of being tried one by one
+                let began = clone.cache.lock().await.begin_write();
+                match began {
+                    Ok(true) => {},
+                    Ok(false) => {
+                        tokio::time::sleep(LOCKED_TASKS_DELAY).await;
+                        continue;
+                    },
+                    Err(e) => {
+                        clone.task_errors.lock().await.push(e);
+                        tokio::time::sleep(LOCKED_TASKS_DELAY).await;
+                        continue;
+                    }
+                }
+                let task = clone.tasks.write().await.pop();
+                let clone = clone.clone();
+                tokio::spawn(async move {
+                    let result = match task {
+                        Some(task) => clone.execute_locked_task(&task).await,
+                        None => Ok(())
+                    };
+                    let ended = clone.cache.lock().await.end_write();
+                    if let Err(e) = result.and(ended) {
+                        clone.task_errors.lock().await.push(e);
+                    }
+                });
                 //clone.next_task();
             }
         });
@@ -270,11 +577,24 @@ impl Embedder {
         *self.tasks.write().await = tasks;
     }
 
-    pub async fn nearest<S>(&mut self, sentence: &S, count: usize) -> Vec<(f32, PathBuf)>
+    /// Returns the chunks nearest to the sentence with their cosine similarity and path, highest first
+    pub async fn nearest<S>(&mut self, sentence: &S, count: usize) -> Result<Vec<(f32, PathBuf, Chunk)>>
     where S: AsRef<str> + Sync + ?Sized {
-        let embeds = self.embed_high_priotity(&[sentence]).await;
-        let embed = embeds[0].as_ref();
-        let cache = self.cache.lock().await;
-        cache.nearest(embed, count)
+        let (embed, cache) = self.embed_query(sentence).await?;
+        cache.nearest(&embed, count)
+    }
+    /// Returns the files nearest to the sentence, the similarities of their chunks combined with aggregation
+    pub async fn nearest_files<S>(&mut self, sentence: &S, count: usize, aggregation: ChunkAggregation) -> Result<Vec<(f32, PathBuf, Chunk, usize)>>
+    where S: AsRef<str> + Sync + ?Sized {
+        let (embed, cache) = self.embed_query(sentence).await?;
+        cache.nearest_files(&embed, count, aggregation)
+    }
+    /// Embeds the sentence and locks the cache, with the last index saved by a build running in an other process
+    async fn embed_query<S>(&self, sentence: &S) -> Result<(Arc<[f32; 384]>, tokio::sync::MutexGuard<'_, Cache>)>
+    where S: AsRef<str> + Sync + ?Sized {
+        let embed = self.embed_high_priotity(&[sentence]).await.remove(0);
+        let mut cache = self.cache.lock().await;
+        cache.reload_if_changed()?;
+        Ok((embed, cache))
     }
 }
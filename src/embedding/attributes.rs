@@ -0,0 +1,146 @@
+/// Filesystem metadata of the items (size, dates, owner, permissions) and the prompts describing it
+
+use std::{path::PathBuf, fs::File, io::Read, time::{SystemTime, UNIX_EPOCH}};
+use crate::error::Result;
+use super::cache::{FileAttributes, Fingerprint};
+
+const MONTHS: [&str; 12] = ["january", "february", "march", "april", "may", "june", "july", "august", "september", "october", "november", "december"];
+const SECONDS_PER_DAY: i64 = 86400;
+/// Files bigger than this aren't hashed, their size and modification date are enough to detect their changes
+const HASH_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+fn timestamp(time: std::io::Result<SystemTime>) -> Option<i64> {
+    time.ok()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs() as i64)
+}
+
+/// Names of the users by uid, /etc/passwd is read once for all the files
+#[cfg(unix)]
+fn get_user_name(uid: u32) -> Option<String> {
+    static USER_NAMES: std::sync::OnceLock<std::collections::HashMap<u32, String>> = std::sync::OnceLock::new();
+    let names = USER_NAMES.get_or_init(|| {
+        let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+        passwd.lines().filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let uid = fields.nth(1)?.parse::<u32>().ok()?;
+            Some((uid, name.to_string()))
+        }).collect()
+    });
+    names.get(&uid).cloned()
+}
+
+pub fn get_file_attributes(path: &PathBuf) -> Result<FileAttributes> {
+    let metadata = std::fs::metadata(path)?;
+    let mut attributes = FileAttributes {
+        size: metadata.len(),
+        modified: timestamp(metadata.modified()),
+        created: timestamp(metadata.created()),
+        owner: None,
+        executable: false,
+        device: None,
+        inode: None
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        attributes.owner = Some(get_user_name(metadata.uid()).unwrap_or(metadata.uid().to_string()));
+        attributes.executable = metadata.is_file() && metadata.permissions().mode() & 0o111 != 0;
+        attributes.device = Some(metadata.dev());
+        attributes.inode = Some(metadata.ino());
+    }
+    Ok(attributes)
+}
+
+/// FNV-1a hash of the content of a file, None for directories, empty and big files. The std hasher isn't used as it can change between versions
+pub fn content_hash(path: &PathBuf) -> Result<Option<u64>> {
+    let metadata = std::fs::metadata(path)?;
+    if !metadata.is_file() || metadata.len() == 0 || metadata.len() > HASH_MAX_SIZE {
+        return Ok(None);
+    }
+    let mut file = File::open(path)?;
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut buffer = [0; 8192];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        for byte in buffer[..n].iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(Some(hash))
+}
+
+/// Fingerprint of the item as it is now on disk, the content is only hashed if with_hash
+pub fn get_fingerprint(path: &PathBuf, with_hash: bool) -> Result<Fingerprint> {
+    let metadata = std::fs::metadata(path)?;
+    Ok(Fingerprint {
+        size: metadata.len(),
+        modified: timestamp(metadata.modified()),
+        content_hash: if with_hash { content_hash(path)? } else { None }
+    })
+}
+
+/// Converts a unix timestamp to (year, month, day) using the algorithm from http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_timestamp(timestamp: i64) -> (i64, usize, i64) {
+    let z = timestamp.div_euclid(SECONDS_PER_DAY) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as usize, day)
+}
+
+/// Formats a timestamp the way it is written in the prompts, for example "18 october 2026"
+pub fn format_date(timestamp: i64) -> String {
+    let (year, month, day) = civil_from_timestamp(timestamp);
+    format!("{} {} {}", day, MONTHS[month - 1], year)
+}
+
+fn size_description(size: u64) -> &'static str {
+    match size {
+        0 => "empty file",
+        1..=1_000_000 => "small file",
+        1_000_001..=100_000_000 => "medium file",
+        _ => "large file"
+    }
+}
+
+pub fn get_attributes_prompts(attributes: &FileAttributes, is_dir: bool) -> Vec<String> {
+    let mut prompts = Vec::new();
+    if !is_dir {
+        prompts.push(format!("size: {}", size_description(attributes.size)));
+    }
+    if let Some(modified) = attributes.modified {
+        prompts.push("modified: ".to_string() + &format_date(modified));
+    }
+    if let Some(created) = attributes.created {
+        prompts.push("created: ".to_string() + &format_date(created));
+    }
+    if let Some(ref owner) = attributes.owner {
+        prompts.push("owner: ".to_string() + owner);
+    }
+    if attributes.executable {
+        prompts.push("executable script".to_string());
+    }
+    prompts
+}
+
+/// Replaces relative dates in a query ("today", "yesterday") by the absolute dates used in the prompts
+pub fn expand_relative_dates(input: &str) -> String {
+    let now = match timestamp(Ok(SystemTime::now())) {
+        Some(now) => now,
+        None => return input.to_string()
+    };
+    input.split(' ').map(|word| match word.to_lowercase().as_str() {
+        "today" => format_date(now),
+        "yesterday" => format_date(now - SECONDS_PER_DAY),
+        _ => word.to_string()
+    }).collect::<Vec<_>>().join(" ")
+}
@@ -1,17 +1,101 @@
-use std::{sync::Arc, path::PathBuf, fmt::{Debug, Formatter}};
-use kdtree::{KdTree, distance::squared_euclidean};
+use std::{sync::Arc, path::{Path, PathBuf}, collections::{HashMap, HashSet}, fmt::{Debug, Formatter}, time::{Duration, Instant, SystemTime}};
 
 mod db;
+mod index;
+mod lock;
 use db::DB;
-use rannoy::Rannoy;
+use index::{VectorIndex, IndexManifest};
+pub use index::IndexBackend;
+use lock::WriteLock;
+use super::{MODEL_ID, attributes};
+use crate::error::{Result, Error};
 
 pub type Id = i32;
-pub type TempCache = KdTree<f32, Id, Arc<[f32]>>;
+
+/// Number of vectors added during a session before the index file is saved again
+const SAVE_INTERVAL: usize = 100;
+/// Number of chunks fetched per file wanted, as a file can have many matching chunks
+const CHUNK_OVERFETCH: usize = 4;
+/// Time during which the vectors of a deleted item are kept, in case it was moved, in seconds
+const TOMBSTONE_RETENTION: i64 = 30 * 86400;
+/// The search only holds the lock for short writes, so the commands writing during all their run wait for it this long
+const LOCK_WAIT: Duration = Duration::from_secs(30);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Converts the squared euclidean distance between two normalized vectors to their cosine similarity
+pub fn similarity_from_squared_distance(squared_distance: f32) -> f32 {
+    1. - squared_distance / 2.
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+/// The manifests of the index files are stored by absolute path, as the cache can be opened from any directory
+fn manifest_key(cache_path: &str) -> PathBuf {
+    std::path::absolute(cache_path).unwrap_or_else(|_| PathBuf::from(cache_path))
+}
+
+/// Modification date and size of the index file, to notice when an other process saved a new one
+fn index_generation(cache_path: &str) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(cache_path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// How the similarities of the chunks of a file are combined into the similarity of the file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkAggregation {
+    /// Similarity of the best chunk
+    Best,
+    /// Mean of the similarities of the k best chunks
+    MeanTopK(usize),
+    /// Sum of the similarities, the nth best chunk weighted by decay^n, so it can be greater than 1
+    SumWithDecay(f32)
+}
+impl ChunkAggregation {
+    /// Parses "best", "mean-top-<k>" or "sum-decay-<decay>"
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name == "best" {
+            return Some(ChunkAggregation::Best);
+        }
+        if let Some(k) = name.strip_prefix("mean-top-") {
+            return k.parse().ok().filter(|&k| k > 0).map(ChunkAggregation::MeanTopK);
+        }
+        let decay = name.strip_prefix("sum-decay-")?.parse::<f32>().ok()?;
+        (0. ..=1.).contains(&decay).then_some(ChunkAggregation::SumWithDecay(decay))
+    }
+    /// Similarities must be sorted highest first and not be empty
+    fn aggregate(&self, similarities: &[f32]) -> f32 {
+        match *self {
+            ChunkAggregation::Best => similarities[0],
+            ChunkAggregation::MeanTopK(k) => {
+                let top = &similarities[..k.max(1).min(similarities.len())];
+                top.iter().sum::<f32>() / top.len() as f32
+            },
+            ChunkAggregation::SumWithDecay(decay) => {
+                similarities.iter().scan(1., |weight, s| {
+                    let weighted = *weight * s;
+                    *weight *= decay;
+                    Some(weighted)
+                }).sum()
+            }
+        }
+    }
+}
+
+/// Files matching in several chunks rank a bit higher than files matching in only one
+impl Default for ChunkAggregation {
+    fn default() -> Self {
+        ChunkAggregation::SumWithDecay(0.1)
+    }
+}
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub enum EmbeddingState {
     None,
     Name,
+    /// Name and metadata embedded in media files (EXIF, audio and video tags)
+    Metadata,
     // (nb of paragraphs) to avoid too much embedding with files with a lot of paragraphs. If the file has more paragraphs the paragraphs will be grouped
     Paragraphs(usize)
 }
@@ -26,6 +110,8 @@ impl PartialOrd for EmbeddingState {
             (_, EmbeddingState::None) => Some(std::cmp::Ordering::Greater),
             (EmbeddingState::Name, _) => Some(std::cmp::Ordering::Less),
             (_, EmbeddingState::Name) => Some(std::cmp::Ordering::Greater),
+            (EmbeddingState::Metadata, _) => Some(std::cmp::Ordering::Less),
+            (_, EmbeddingState::Metadata) => Some(std::cmp::Ordering::Greater),
             (EmbeddingState::Paragraphs(a), EmbeddingState::Paragraphs(b)) => Some(a.cmp(b)),
         }
     }
@@ -42,73 +128,497 @@ pub struct CacheItem {
     pub state: EmbeddingState
 }
 
+/// Filesystem metadata of an item, dates are unix timestamps in seconds
+#[derive(Debug, Clone, Default)]
+pub struct FileAttributes {
+    pub size: u64,
+    pub modified: Option<i64>,
+    pub created: Option<i64>,
+    pub owner: Option<String>,
+    pub executable: bool,
+    /// Device and inode, to recognize a file that was moved
+    pub device: Option<u64>,
+    pub inode: Option<u64>
+}
+
+/// State of an item on disk when it was embedded, to detect the changes made since
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub size: u64,
+    pub modified: Option<i64>,
+    /// None if the item is a directory or too big to be hashed
+    pub content_hash: Option<u64>
+}
+
+/// Part of an item a vector was computed from
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    /// Position of the vector among the vectors of the item
+    pub index: usize,
+    /// Byte range in the content of the file, None if the vector doesn't come from the content
+    pub range: Option<(usize, usize)>,
+    pub excerpt: Option<String>
+}
+
 pub struct Cache {
-    temp_cache: TempCache,
-    annoy: Option<Rannoy>,
+    /// Index of the vectors, the ids are the ids of the embeddings in the DB
+    index: Box<dyn VectorIndex>,
+    backend: IndexBackend,
+    cache_path: Option<String>,
+    /// Number of changes of the index since it was saved
+    unsaved: usize,
+    /// Ids of the embeddings added to the index that aren't marked as indexed in the DB yet
+    unindexed: HashSet<Id>,
+    /// Paths the index was built from, kept in its manifest
+    roots: Vec<PathBuf>,
+    /// Generation of the index file the index was loaded from or saved to
+    generation: Option<(SystemTime, u64)>,
+    /// Path of the lock file, None if the DB is in memory
+    lock_path: Option<PathBuf>,
+    write_lock: Option<WriteLock>,
+    /// Number of writes in progress, the lock is released when it gets back to 0
+    writers: usize,
     db: DB
 }
 impl Debug for Cache {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Cache")
-            .field("temp_cache", &self.temp_cache)
+            .field("backend", &self.backend)
+            .field("index_len", &self.index.len())
+            .field("cache_path", &self.cache_path)
+            .field("write_lock", &self.write_lock)
             .field("db", &self.db)
             .finish()
     }
 }
 impl Cache {
-    pub fn new(db_path: Option<String>, cache_path: Option<String>) -> Self {
-        let annoy = match cache_path {
-            Some(p) => {
-                let a = Rannoy::new(384);
-                a.set_seed(123);
-                a.load(p.into());
-                Some(a)
-            },
-            None => None
+    /// With rebuild, the index file is neither checked nor loaded, as the caller is about to write it again
+    pub fn new(db_path: Option<String>, cache_path: Option<String>, backend: IndexBackend, rebuild: bool) -> Result<Self> {
+        let lock_path = db_path.as_ref().map(|p| PathBuf::from(format!("{}.lock", p)));
+        let db = DB::new(db_path)?;
+        let mut roots = Vec::new();
+        if let Some(ref cache_path) = cache_path {
+            if let Some(manifest) = db.get_index_manifest(&manifest_key(cache_path))? {
+                // A file built by an other backend or model would give meaningless results, or fail to load
+                if !rebuild && Path::new(cache_path).exists() {
+                    if let Some(mismatch) = manifest.check(backend, MODEL_ID) {
+                        return Err(Error::IndexMismatch(format!("{}: {}, run with --reindex to build it again", cache_path, mismatch)));
+                    }
+                }
+                roots = manifest.roots;
+            }
+        }
+        let generation = cache_path.as_deref().and_then(index_generation);
+        let loaded = cache_path.as_ref().filter(|_| !rebuild).and_then(|p| backend.load(p).ok());
+        let (mut index, only_unindexed) = match loaded {
+            Some(index) => (index, true),
+            None => (backend.create(), false)
+        };
+        // Add the vectors that aren't in the index file yet, rebuild_index adds them all anyway
+        let mut unindexed = HashSet::new();
+        if !rebuild {
+            for (embedding_id, _, vector) in db.get_embeddings(MODEL_ID, only_unindexed)? {
+                index.add(embedding_id, vector);
+                unindexed.insert(embedding_id);
+            }
+        }
+        Ok(Self {
+            index,
+            backend,
+            cache_path,
+            unsaved: unindexed.len(),
+            unindexed,
+            roots,
+            generation,
+            lock_path,
+            write_lock: None,
+            writers: 0,
+            db
+        })
+    }
+    /// Takes the lock for writing if nobody else holds it, returns false otherwise.
+    /// Every successful call must be followed by a call to end_write
+    pub fn begin_write(&mut self) -> Result<bool> {
+        if self.writers == 0 && self.write_lock.is_none() {
+            if let Some(ref lock_path) = self.lock_path {
+                match WriteLock::try_acquire(lock_path.clone())? {
+                    Some(lock) => self.write_lock = Some(lock),
+                    None => return Ok(false)
+                }
+                // The previous writer may have saved a new index in the meantime
+                self.reload_index_if_changed()?;
+            }
+        }
+        self.writers += 1;
+        Ok(true)
+    }
+    pub fn end_write(&mut self) -> Result<()> {
+        self.writers = self.writers.saturating_sub(1);
+        if self.writers == 0 && self.write_lock.is_some() {
+            let saved = self.save_if_needed();
+            self.write_lock = None;
+            saved?;
+        }
+        Ok(())
+    }
+    /// Holds the lock until the cache is dropped, for the commands that write during all their run.
+    /// Fails if another process still holds it after LOCK_WAIT
+    pub fn lock_for_writing(&mut self) -> Result<()> {
+        let start = Instant::now();
+        while !self.begin_write()? {
+            if start.elapsed() >= LOCK_WAIT {
+                let lock_path = self.lock_path.as_ref().map(|p| p.display().to_string()).unwrap_or_default();
+                return Err(Error::DatabaseLocked(lock_path));
+            }
+            std::thread::sleep(LOCK_RETRY_INTERVAL);
+        }
+        Ok(())
+    }
+    fn can_write(&self) -> bool {
+        self.lock_path.is_none() || self.write_lock.is_some()
+    }
+    /// Loads the index file again if an other process saved a new one, and returns true if it did.
+    /// The writer never reloads as it's the only one saving the index file
+    pub fn reload_if_changed(&mut self) -> Result<bool> {
+        if self.write_lock.is_some() {
+            return Ok(false);
+        }
+        self.reload_index_if_changed()
+    }
+    fn reload_index_if_changed(&mut self) -> Result<bool> {
+        let cache_path = match self.cache_path {
+            Some(ref cache_path) => cache_path.clone(),
+            None => return Ok(false)
+        };
+        let generation = index_generation(&cache_path);
+        if generation.is_none() || generation == self.generation {
+            return Ok(false);
+        }
+        // The file may have been replaced by one from an other embedder
+        if let Some(manifest) = self.db.get_index_manifest(&manifest_key(&cache_path))? {
+            if manifest.check(self.backend, MODEL_ID).is_some() {
+                return Ok(false);
+            }
+        }
+        // A file being replaced can fail to load, the current index is kept until the next try
+        let index = match self.backend.load(&cache_path) {
+            Ok(index) => index,
+            Err(_) => return Ok(false)
         };
-        Self {
-            temp_cache: KdTree::new(384),
-            annoy,
-            db: DB::new(db_path)
+        self.index = index;
+        self.generation = generation;
+        self.unindexed.clear();
+        self.unsaved = 0;
+        // The vectors added since, including the ones of this process, are still unindexed in the DB
+        for (embedding_id, _, vector) in self.db.get_embeddings(MODEL_ID, true)? {
+            self.add_to_index(embedding_id, vector);
         }
+        Ok(true)
+    }
+    fn add_to_index(&mut self, embedding_id: Id, vector: [f32; 384]) {
+        self.index.add(embedding_id, vector);
+        self.unindexed.insert(embedding_id);
+        self.unsaved += 1;
     }
-    pub fn create_item(&self, item: &CacheItem) {
+    pub fn create_item(&self, item: &CacheItem) -> Result<()> {
         self.db.insert_item(item)
     }
-    pub fn create_or_update_item(&self, item: &CacheItem) {
+    pub fn create_or_update_item(&self, item: &CacheItem) -> Result<()> {
         self.db.insert_or_update_item(item)
     }
-    pub fn add_embed_to_id(&mut self, embed: Arc<[f32; 384]>, id: Id) {
-        self.temp_cache.add(embed, id).expect("Can't add item to temp cache")
-    }
-    pub fn nearest(&self, embed: &[f32; 384], count: usize) -> Vec<(f32, PathBuf)> {
-        let nearest = match self.annoy {
-            None => Vec::new(),
-            Some(ref a) => {
-                let result = a.get_nns_by_vector((*embed).into(), count.try_into().unwrap(), -1); // search_k can be changed to increase precision but it's slower
-                eprintln!("{:#?}", result);
-                result.1.into_iter().zip(result.0.into_iter().map(|id|{
-                    self.db.get_path_by_id(id).expect(format!("Trying to get id that doesn't exist from db : {}", id).as_str())
-                })).collect()
+    pub fn set_attributes(&self, path: &PathBuf, attributes: &FileAttributes) -> Result<()> {
+        if let Some(id) = self.db.get_id_by_path(path)? {
+            self.db.update_attributes(id, attributes)?;
+        }
+        Ok(())
+    }
+    pub fn set_content_hash(&self, path: &PathBuf, content_hash: Option<u64>) -> Result<()> {
+        if let Some(id) = self.db.get_id_by_path(path)? {
+            self.db.update_content_hash(id, content_hash)?;
+        }
+        Ok(())
+    }
+    /// Returns true if the item changed on disk since it was embedded.
+    /// A file only touched isn't stale, its new modification date is stored so that it isn't hashed again
+    pub fn is_stale(&self, path: &PathBuf) -> Result<bool> {
+        self.is_stale_with(path, || attributes::content_hash(path).ok().flatten())
+    }
+    /// Returns true if only the hash of its content can tell whether the item is stale: its size is the same but its modification date changed
+    pub fn needs_content_hash(&self, path: &PathBuf) -> Result<bool> {
+        let (_, stored) = match self.db.get_fingerprint_by_path(path)? {
+            Some(fingerprint) => fingerprint,
+            None => return Ok(false)
+        };
+        Ok(match attributes::get_fingerprint(path, false) {
+            Ok(current) => current.size == stored.size && current.modified != stored.modified && stored.content_hash.is_some(),
+            Err(_) => false
+        })
+    }
+    fn is_stale_with(&self, path: &PathBuf, content_hash: impl FnOnce() -> Option<u64>) -> Result<bool> {
+        let (id, stored) = match self.db.get_fingerprint_by_path(path)? {
+            Some(fingerprint) => fingerprint,
+            None => return Ok(false)
+        };
+        let current = match attributes::get_fingerprint(path, false) {
+            Ok(fingerprint) => fingerprint,
+            Err(_) => return Ok(false)
+        };
+        if current.size != stored.size {
+            return Ok(true);
+        }
+        if current.modified == stored.modified {
+            return Ok(false);
+        }
+        match (stored.content_hash, content_hash()) {
+            (Some(stored_hash), Some(hash)) if stored_hash == hash => {
+                self.db.update_modified(id, current.modified)?;
+                Ok(false)
+            },
+            _ => Ok(true)
+        }
+    }
+    /// Returns true if the size or the modification date of the item changed since it was embedded, without reading the file.
+    /// A file only touched is modified but not stale
+    pub fn is_modified(&self, path: &PathBuf) -> Result<bool> {
+        let (_, stored) = match self.db.get_fingerprint_by_path(path)? {
+            Some(fingerprint) => fingerprint,
+            None => return Ok(false)
+        };
+        Ok(match attributes::get_fingerprint(path, false) {
+            Ok(current) => current.size != stored.size || current.modified != stored.modified,
+            Err(_) => false
+        })
+    }
+    /// Removes the vectors of an item that changed since it was embedded, and returns true if it did.
+    /// content_hash is the current hash of the file when needs_content_hash asked for it, computed by the caller without holding the cache
+    pub fn invalidate_if_stale(&mut self, path: &PathBuf, content_hash: Option<u64>) -> Result<bool> {
+        if !self.is_stale_with(path, || content_hash)? {
+            return Ok(false);
+        }
+        if let Some(id) = self.db.get_id_by_path(path)? {
+            self.clear_embeds(id)?;
+            self.db.update_item(id, &CacheItem { path: path.clone(), state: EmbeddingState::None })?;
+        }
+        Ok(true)
+    }
+    /// Marks the items whose path doesn't exist anymore as deleted and removes their vectors from the index.
+    /// Their vectors stay in the DB for some time, so that they can be given back to the item if it was moved.
+    /// Returns the number of items marked as deleted and the number of items removed from the DB
+    pub fn prune(&mut self) -> Result<(usize, usize)> {
+        let now = now();
+        let deleted = self.mark_deleted_items(None, now)?;
+        let removed = self.db.delete_items_deleted_before(now - TOMBSTONE_RETENTION)?;
+        Ok((deleted.len(), removed))
+    }
+    /// Marks the items under root whose path doesn't exist anymore as deleted, and returns their paths
+    pub fn prune_under(&mut self, root: &Path) -> Result<Vec<PathBuf>> {
+        self.mark_deleted_items(Some(root), now())
+    }
+    fn mark_deleted_items(&mut self, root: Option<&Path>, now: i64) -> Result<Vec<PathBuf>> {
+        let mut deleted = Vec::new();
+        for (id, path) in self.db.get_present_items()? {
+            if root.map_or(false, |root| !path.starts_with(root)) {
+                continue;
             }
+            // Errors like a permission denied don't mean the file is gone
+            if let Ok(false) = path.try_exists() {
+                for embedding_id in self.db.get_embedding_ids_by_item(id)? {
+                    self.index.remove(embedding_id);
+                    self.unsaved += 1;
+                }
+                self.db.set_deleted(id, now)?;
+                deleted.push(path);
+            }
+        }
+        Ok(deleted)
+    }
+    /// Gives the vectors of a deleted item with the same inode or content to the item at path, and returns its id if there was one.
+    /// Only the vectors of the content are kept, the others describe the old name and location
+    pub fn move_deleted_item(&mut self, path: &PathBuf, state: EmbeddingState, attributes: &FileAttributes, content_hash: Option<u64>) -> Result<Option<Id>> {
+        let id = match self.db.find_deleted_item(attributes, content_hash, state)? {
+            Some(id) => id,
+            None => return Ok(None)
         };
-        let temp_nearest = self.temp_cache.nearest(embed, count, &squared_euclidean).expect("Can't get nearest in temp cache");
-        let temp_nearest: Vec<(f32, PathBuf)> = temp_nearest.into_iter().map(|(score, id)| (
-            score,
-            self.db.get_path_by_id(*id).expect(format!("Trying to get id that doesn't exist from db : {}", id).as_str())
-        )).collect();
-        let mut nearest = nearest.into_iter().chain(temp_nearest.into_iter()).collect::<Vec<_>>();
-        nearest.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-        nearest.truncate(count);
-        nearest
-    }
-    pub fn contains(&self, item: &CacheItem) -> bool {
-        match self.db.get_state_by_path(&item.path) {
-            Some(state) => state >= item.state,
-            None => false
+        // The item at path, if any, must be the deleted item itself
+        if self.db.get_id_by_path(path)?.map_or(false, |other| other != id) {
+            return Ok(None);
+        }
+        self.db.delete_other_embeddings_by_item(id)?;
+        self.db.move_item(id, path)?;
+        self.db.update_attributes(id, attributes)?;
+        self.db.update_content_hash(id, content_hash)?;
+        for (embedding_id, vector) in self.db.get_embeddings_by_item(id, MODEL_ID)? {
+            self.add_to_index(embedding_id, vector);
+        }
+        Ok(Some(id))
+    }
+    /// Returns an other item with this content, embedded at least at state and unchanged since.
+    /// The copies are compared by size and modification date only, as hashing them would hold the cache, so a copy only touched isn't used
+    pub fn find_duplicate(&self, content_hash: u64, path: &PathBuf, state: EmbeddingState) -> Result<Option<Id>> {
+        for (id, other_path, other_state) in self.db.get_items_by_content_hash(content_hash)? {
+            if &other_path != path && other_state >= state && !self.is_modified(&other_path)? {
+                return Ok(Some(id));
+            }
+        }
+        Ok(None)
+    }
+    /// Gives to an item the vectors computed from the content of another item, without running the model.
+    /// Its old vectors are removed, they describe a content it doesn't have anymore
+    pub fn copy_content_embeds(&mut self, from: Id, to: Id) -> Result<()> {
+        self.clear_embeds(to)?;
+        for (chunk, vector) in self.db.get_content_embeddings_by_item(from, MODEL_ID)? {
+            self.store_embed(&vector, to, chunk.range, chunk.excerpt)?;
+        }
+        Ok(())
+    }
+    /// Returns the vectors stored for an item
+    pub fn get_embeds(&self, id: Id) -> Result<Vec<Arc<[f32; 384]>>> {
+        Ok(self.db.get_vectors_by_item(id, MODEL_ID)?.into_iter().map(Arc::new).collect())
+    }
+    pub fn store_embed(&mut self, embed: &[f32; 384], id: Id, range: Option<(usize, usize)>, excerpt: Option<String>) -> Result<()> {
+        let embedding_id = self.db.insert_embedding(id, embed, MODEL_ID, range, excerpt)?;
+        self.add_to_index(embedding_id, *embed);
+        Ok(())
+    }
+    pub fn clear_embeds(&mut self, id: Id) -> Result<()> {
+        for embedding_id in self.db.get_embedding_ids_by_item(id)? {
+            self.index.remove(embedding_id);
+            self.unsaved += 1;
+        }
+        self.db.delete_embeddings_by_item(id)
+    }
+    pub fn add_embed_to_id(&mut self, embed: Arc<[f32; 384]>, id: Id, range: Option<(usize, usize)>, excerpt: Option<String>) -> Result<()> {
+        self.store_embed(&embed, id, range, excerpt)
+    }
+    /// Writes the index file, if the cache has one and holds the lock
+    pub fn save(&mut self) -> Result<()> {
+        if !self.can_write() {
+            return Ok(());
+        }
+        if let Some(ref cache_path) = self.cache_path {
+            self.index.save(cache_path)?;
+            self.generation = index_generation(cache_path);
+            let manifest = IndexManifest::new(self.backend, MODEL_ID, now(), self.roots.clone(), self.index.len());
+            self.db.set_index_manifest(&manifest_key(cache_path), &manifest)?;
+            let indexed = self.unindexed.drain().collect::<Vec<_>>();
+            self.db.set_embeddings_indexed(&indexed)?;
         }
+        self.unsaved = 0;
+        Ok(())
     }
-    pub fn get_id_by_path(&self, path: &PathBuf) -> Option<Id> {
+    /// Records a path the index is built from in its manifest, at the next save
+    pub fn add_root(&mut self, root: &Path) {
+        if !self.roots.iter().any(|r| r == root) {
+            self.roots.push(root.to_path_buf());
+        }
+    }
+    /// Records that the files of directory were all embedded at state, so that a resumed build of root doesn't check them again
+    pub fn add_processed_directory(&self, root: &Path, directory: &Path, state: EmbeddingState) -> Result<()> {
+        self.db.insert_processed_directory(root, directory, state)
+    }
+    /// Returns the directories recorded by an interrupted build of root at state
+    pub fn get_processed_directories(&self, root: &Path, state: EmbeddingState) -> Result<HashSet<PathBuf>> {
+        Ok(self.db.get_processed_directories(root, state)?.into_iter().collect())
+    }
+    pub fn clear_processed_directories(&self, root: &Path) -> Result<()> {
+        self.db.delete_processed_directories(root)
+    }
+    /// Saves the vectors not in the index file yet, taking the lock if the cache doesn't hold it.
+    /// Returns false if another process holds the lock, the vectors stay in the DB and are indexed by the next session.
+    /// Dropping the cache only releases the lock, so the sessions end with this
+    pub fn flush(&mut self) -> Result<bool> {
+        if self.unsaved == 0 {
+            return Ok(true);
+        }
+        if !self.begin_write()? {
+            return Ok(false);
+        }
+        let saved = self.save();
+        self.end_write()?;
+        saved.map(|_| true)
+    }
+    pub fn save_if_needed(&mut self) -> Result<()> {
+        if self.unsaved >= SAVE_INTERVAL {
+            self.save()?;
+        }
+        Ok(())
+    }
+    /// Builds the index again from all the vectors stored in the DB and returns the number of vectors indexed
+    pub fn rebuild_index(&mut self) -> Result<usize> {
+        self.index = self.backend.create();
+        for (embedding_id, _, vector) in self.db.get_embeddings(MODEL_ID, false)? {
+            self.add_to_index(embedding_id, vector);
+        }
+        self.save()?;
+        Ok(self.index.len())
+    }
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+    /// Returns the nearest chunks with their cosine similarity in [-1, 1] and their path, highest first
+    pub fn nearest(&self, embed: &[f32; 384], count: usize) -> Result<Vec<(f32, PathBuf, Chunk)>> {
+        let mut nearest = Vec::new();
+        for (distance, id) in self.index.search(embed, count) {
+            // Items deleted since the last prune are skipped
+            if let Some((path, chunk)) = self.db.get_chunk_by_embedding_id(id)? {
+                if path.exists() {
+                    nearest.push((similarity_from_squared_distance(distance), path, chunk));
+                }
+            }
+        }
+        Ok(nearest)
+    }
+    /// Returns the nearest files with their aggregated similarity, their path, their best chunk and their number of copies, highest first.
+    /// Only the best of the files with the same content is returned
+    pub fn nearest_files(&self, embed: &[f32; 384], count: usize, aggregation: ChunkAggregation) -> Result<Vec<(f32, PathBuf, Chunk, usize)>> {
+        let mut fetch = count * CHUNK_OVERFETCH;
+        loop {
+            let chunks = self.nearest(embed, fetch)?;
+
+            // Chunks are sorted so the first chunk of each file is its best one
+            let mut files: HashMap<PathBuf, (Vec<f32>, Chunk)> = HashMap::new();
+            for (similarity, path, chunk) in chunks {
+                files.entry(path).or_insert_with(|| (Vec::new(), chunk)).0.push(similarity);
+            }
+
+            let mut files = files.into_iter()
+                .map(|(path, (similarities, chunk))| (aggregation.aggregate(&similarities), path, chunk))
+                .collect::<Vec<_>>();
+            files.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+
+            let mut contents = HashSet::new();
+            let mut distinct_files = Vec::new();
+            for (similarity, path, chunk) in files {
+                match self.db.get_content_hash_by_path(&path)? {
+                    Some(content_hash) => if contents.insert(content_hash) {
+                        let copies = self.db.count_items_by_content_hash(content_hash)?.saturating_sub(1);
+                        distinct_files.push((similarity, path, chunk, copies));
+                    },
+                    None => distinct_files.push((similarity, path, chunk, 0))
+                }
+            }
+
+            if distinct_files.len() >= count || fetch >= self.index.len() {
+                distinct_files.truncate(count);
+                return Ok(distinct_files);
+            }
+            fetch *= 2;
+        }
+    }
+    pub fn contains(&self, item: &CacheItem) -> Result<bool> {
+        Ok(match self.db.get_state_by_path(&item.path)? {
+            Some(state) => state >= item.state && !self.is_stale(&item.path)?,
+            None => false
+        })
+    }
+    /// Like contains, but a modified item isn't hashed to know if its content changed, for the walks that must stay fast
+    pub fn contains_unmodified(&self, item: &CacheItem) -> Result<bool> {
+        Ok(match self.db.get_state_by_path(&item.path)? {
+            Some(state) => state >= item.state && !self.is_modified(&item.path)?,
+            None => false
+        })
+    }
+    pub fn get_id_by_path(&self, path: &PathBuf) -> Result<Option<Id>> {
         self.db.get_id_by_path(path)
     }
-}
\ No newline at end of file
+}
@@ -1,18 +1,26 @@
-use std::path::PathBuf;
+use std::{path::{Path, PathBuf}, time::Duration};
 use rusqlite::OptionalExtension;
 use rusqlite::ToSql;
 use rusqlite::params;
 use rusqlite::Connection;
 use rusqlite::types::FromSql;
 use super::CacheItem;
+use super::FileAttributes;
+use super::Fingerprint;
+use super::Chunk;
 use super::EmbeddingState;
 use super::Id;
+use super::index::{IndexBackend, IndexManifest};
+use crate::error::{Result, Error};
 
+// Paragraphs(n) is stored as 2 + n since the first databases, so Metadata takes a negative value no other state uses.
+// The values don't follow the order of the states, so the states are compared in Rust and not in SQL
 impl ToSql for EmbeddingState {
     fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
         Ok(match self {
             EmbeddingState::None => 0.into(),
             EmbeddingState::Name => 1.into(),
+            EmbeddingState::Metadata => (-1).into(),
             EmbeddingState::Paragraphs(n) => (2 + (*n as u32)).into()
         })
     }
@@ -22,67 +30,417 @@ impl FromSql for EmbeddingState {
         Ok(match value.as_i64()? {
             0 => EmbeddingState::None,
             1 => EmbeddingState::Name,
+            -1 => EmbeddingState::Metadata,
             n => EmbeddingState::Paragraphs((n - 2) as usize)
         })
     }
 }
 
+/// Vectors are stored as the little endian bytes of their components
+fn vector_to_blob(vector: &[f32; 384]) -> Vec<u8> {
+    vector.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+fn blob_to_vector(blob: &[u8]) -> Option<[f32; 384]> {
+    if blob.len() != 384 * 4 {
+        return None;
+    }
+    let mut vector = [0.; 384];
+    for (v, bytes) in vector.iter_mut().zip(blob.chunks_exact(4)) {
+        *v = f32::from_le_bytes(bytes.try_into().ok()?);
+    }
+    Some(vector)
+}
+
+/// Paths are stored as the bytes of the OS, so that names that aren't valid UTF-8 keep matching their row
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> &[u8] {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes()
+}
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    std::ffi::OsString::from_vec(bytes).into()
+}
+// Other systems don't give their raw bytes, their paths are almost always valid unicode anyway
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+/// Migrations from the version at their index to the next one, the version 0 being a new database.
+/// Databases created before the versioning also have the version 0, so the migrations must work on any older schema
+const MIGRATIONS: &[fn(&Connection) -> rusqlite::Result<()>] = &[
+    create_items,
+    add_attributes,
+    create_embeddings,
+    add_chunk_offsets,
+    add_content_hash,
+    add_tombstones,
+    store_paths_as_bytes,
+    create_indexes,
+    create_build_progress
+];
+/// Version of the schema written by this binary, stored in the user_version of the database
+const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+/// Time a query waits for the database to be unlocked by an other process before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<()> {
+    let exists = conn.prepare(&format!("SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1", table))?.exists(params![column])?;
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))?;
+    }
+    Ok(())
+}
+
+fn create_items(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS items (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            path TEXT UNIQUE NOT NULL,
+            state INTEGER NOT NULL DEFAULT 0
+        );
+    ")
+}
+fn add_attributes(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "items", "size", "INTEGER")?;
+    add_column_if_missing(conn, "items", "modified", "INTEGER")?;
+    add_column_if_missing(conn, "items", "created", "INTEGER")?;
+    add_column_if_missing(conn, "items", "owner", "TEXT")?;
+    add_column_if_missing(conn, "items", "executable", "INTEGER NOT NULL DEFAULT 0")
+}
+fn create_embeddings(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS embeddings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            item_id INTEGER NOT NULL REFERENCES items(id),
+            chunk INTEGER NOT NULL DEFAULT 0,
+            vector BLOB NOT NULL,
+            model TEXT NOT NULL,
+            indexed INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE INDEX IF NOT EXISTS embeddings_item_id ON embeddings(item_id);
+    ")
+}
+fn add_chunk_offsets(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "embeddings", "start_byte", "INTEGER")?;
+    add_column_if_missing(conn, "embeddings", "end_byte", "INTEGER")?;
+    add_column_if_missing(conn, "embeddings", "excerpt", "TEXT")
+}
+fn add_content_hash(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "items", "content_hash", "INTEGER")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS items_content_hash ON items(content_hash);")
+}
+fn add_tombstones(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "items", "device", "INTEGER")?;
+    add_column_if_missing(conn, "items", "inode", "INTEGER")?;
+    add_column_if_missing(conn, "items", "deleted", "INTEGER")
+}
+/// A blob is never equal to a text in SQLite, so the paths stored as text are converted
+fn store_paths_as_bytes(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("UPDATE items SET path = CAST(path AS BLOB) WHERE typeof(path) = 'text';")
+}
+fn create_indexes(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS indexes (
+            path BLOB PRIMARY KEY NOT NULL,
+            backend TEXT NOT NULL,
+            model TEXT NOT NULL,
+            dimension INTEGER NOT NULL,
+            metric TEXT NOT NULL,
+            trees INTEGER,
+            seed INTEGER,
+            built INTEGER NOT NULL,
+            items INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS index_roots (
+            index_path BLOB NOT NULL REFERENCES indexes(path),
+            root BLOB NOT NULL,
+            UNIQUE(index_path, root)
+        );
+    ")
+}
+fn create_build_progress(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS build_progress (
+            root BLOB NOT NULL,
+            directory BLOB NOT NULL,
+            state INTEGER NOT NULL,
+            UNIQUE(root, directory)
+        );
+    ")
+}
+
 #[derive(Debug)]
 pub struct DB {
     conn: Connection
 }
 impl DB {
     /// If None is used as a path, the database is opened in memory
-    pub fn new(path: Option<String>) -> Self {
+    pub fn new(path: Option<String>) -> Result<Self> {
         let conn = match path {
-            Some(path) => Connection::open(path).expect("Cannot open DB"),
-            None => Connection::open_in_memory().expect("Cannot open DB")
+            Some(path) => Connection::open(path)?,
+            None => Connection::open_in_memory()?
         };
+        // WAL lets the readers query the database while a build writes it, and the other processes wait instead of failing when it is busy
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
 
-        let db = Self{conn};
-        db.create_tables();
+        let mut db = Self{conn};
+        db.migrate()?;
 
-        db
+        Ok(db)
     }
 
-    pub fn create_tables(&self) {
-        self.conn.execute("
-            CREATE TABLE IF NOT EXISTS items (
-                id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-                path TEXT UNIQUE NOT NULL,
-                state INTEGER NOT NULL DEFAULT 0
-            );
-        ", []).expect("Can't create DB tables");
+    /// Applies the migrations the database doesn't have yet, each one in a transaction
+    pub fn migrate(&mut self) -> Result<()> {
+        let version: u32 = self.conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(Error::UnsupportedSchemaVersion(version));
+        }
+        for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let transaction = self.conn.transaction()?;
+            migration(&transaction)?;
+            transaction.pragma_update(None, "user_version", from as u32 + 1)?;
+            transaction.commit()?;
+        }
+        Ok(())
     }
 
-    pub fn insert_item(&self, item: &CacheItem) {
-        self.conn.execute("INSERT INTO items (path, state) VALUES (?1, ?2)", params![item.path.to_string_lossy(), item.state]).expect("Can't insert element");
+    pub fn insert_item(&self, item: &CacheItem) -> Result<()> {
+        self.conn.execute("INSERT INTO items (path, state) VALUES (?1, ?2)", params![path_to_bytes(&item.path), item.state])?;
+        Ok(())
     }
-    pub fn update_item(&self, id: Id, item: &CacheItem) {
-        self.conn.execute("UPDATE items SET path = ?1, state = ?2 WHERE id = ?3", params![item.path.to_string_lossy(), item.state, id]).expect("Can't update element");
+    /// Updating a deleted item brings it back
+    pub fn update_item(&self, id: Id, item: &CacheItem) -> Result<()> {
+        self.conn.execute("UPDATE items SET path = ?1, state = ?2, deleted = NULL WHERE id = ?3", params![path_to_bytes(&item.path), item.state, id])?;
+        Ok(())
     }
-    pub fn insert_or_update_item(&self, item: &CacheItem) {
-        match self.get_id_by_path(&item.path) {
+    pub fn insert_or_update_item(&self, item: &CacheItem) -> Result<()> {
+        match self.get_id_by_path(&item.path)? {
             Some(id) => self.update_item(id, item),
             None => self.insert_item(item)
         }
     }
-    pub fn get_id_by_path(&self, path: &PathBuf) -> Option<Id> {
-        self.conn.query_row("SELECT id FROM items WHERE path = ?1", params![path.to_str().expect("Can't do path to str")], |row| row.get(0)).optional().expect("Can't get id from path")
+    pub fn get_id_by_path(&self, path: &PathBuf) -> Result<Option<Id>> {
+        Ok(self.conn.query_row("SELECT id FROM items WHERE path = ?1", params![path_to_bytes(path)], |row| row.get(0)).optional()?)
+    }
+    pub fn get_state_by_path(&self, path: &PathBuf) -> Result<Option<EmbeddingState>> {
+        Ok(self.conn.query_row("SELECT state FROM items WHERE path = ?1 AND deleted IS NULL", params![path_to_bytes(path)], |row| row.get(0)).optional()?)
+    }
+    pub fn update_attributes(&self, id: Id, attributes: &FileAttributes) -> Result<()> {
+        self.conn.execute("UPDATE items SET size = ?1, modified = ?2, created = ?3, owner = ?4, executable = ?5, device = ?6, inode = ?7 WHERE id = ?8", params![attributes.size as i64, attributes.modified, attributes.created, attributes.owner, attributes.executable, attributes.device.map(|d| d as i64), attributes.inode.map(|i| i as i64), id])?;
+        Ok(())
+    }
+    /// Returns the items not marked as deleted
+    pub fn get_present_items(&self) -> Result<Vec<(Id, PathBuf)>> {
+        let mut stmt = self.conn.prepare("SELECT id, path FROM items WHERE deleted IS NULL")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, path_from_bytes(row.get(1)?))))?;
+        let items = rows.collect::<rusqlite::Result<_>>()?;
+        Ok(items)
+    }
+    /// Marks an item as deleted at the timestamp
+    pub fn set_deleted(&self, id: Id, timestamp: i64) -> Result<()> {
+        self.conn.execute("UPDATE items SET deleted = ?1 WHERE id = ?2", params![timestamp, id])?;
+        Ok(())
+    }
+    /// Removes the items deleted before the timestamp and their vectors, and returns their number
+    pub fn delete_items_deleted_before(&self, timestamp: i64) -> Result<usize> {
+        self.conn.execute("DELETE FROM embeddings WHERE item_id IN (SELECT id FROM items WHERE deleted < ?1)", params![timestamp])?;
+        Ok(self.conn.execute("DELETE FROM items WHERE deleted < ?1", params![timestamp])?)
+    }
+    /// Returns a deleted item embedded at least at state, with the same content or the same inode, size and modification date.
+    /// Inodes are reused by the filesystem, so the inode alone doesn't identify a moved file
+    pub fn find_deleted_item(&self, attributes: &FileAttributes, content_hash: Option<u64>, state: EmbeddingState) -> Result<Option<Id>> {
+        let mut stmt = self.conn.prepare("SELECT id, state FROM items WHERE deleted IS NOT NULL AND ((device = ?1 AND inode = ?2 AND size = ?3 AND modified IS ?4) OR content_hash = ?5) ORDER BY deleted DESC")?;
+        let rows = stmt.query_map(params![attributes.device.map(|d| d as i64), attributes.inode.map(|i| i as i64), attributes.size as i64, attributes.modified, content_hash.map(|h| h as i64)], |row| Ok((row.get::<_, Id>(0)?, row.get::<_, EmbeddingState>(1)?)))?;
+        for row in rows {
+            let (id, other_state) = row?;
+            if other_state >= state {
+                return Ok(Some(id));
+            }
+        }
+        Ok(None)
+    }
+    /// Gives a new path to an item and brings it back if it was deleted
+    pub fn move_item(&self, id: Id, path: &PathBuf) -> Result<()> {
+        self.conn.execute("UPDATE items SET path = ?1, deleted = NULL WHERE id = ?2", params![path_to_bytes(path), id])?;
+        // Its vectors aren't in the index file anymore
+        self.conn.execute("UPDATE embeddings SET indexed = 0 WHERE item_id = ?1", params![id])?;
+        Ok(())
+    }
+    /// Hashes are stored as i64, the INTEGER type of SQLite
+    pub fn update_content_hash(&self, id: Id, content_hash: Option<u64>) -> Result<()> {
+        self.conn.execute("UPDATE items SET content_hash = ?1 WHERE id = ?2", params![content_hash.map(|h| h as i64), id])?;
+        Ok(())
+    }
+    pub fn update_modified(&self, id: Id, modified: Option<i64>) -> Result<()> {
+        self.conn.execute("UPDATE items SET modified = ?1 WHERE id = ?2", params![modified, id])?;
+        Ok(())
+    }
+    pub fn get_content_hash_by_path(&self, path: &PathBuf) -> Result<Option<u64>> {
+        Ok(self.conn.query_row("SELECT content_hash FROM items WHERE path = ?1", params![path_to_bytes(path)], |row| row.get::<_, Option<i64>>(0)).optional()?.flatten().map(|h| h as u64))
+    }
+    /// Returns (id, path, state) of the items with this content
+    pub fn get_items_by_content_hash(&self, content_hash: u64) -> Result<Vec<(Id, PathBuf, EmbeddingState)>> {
+        let mut stmt = self.conn.prepare("SELECT id, path, state FROM items WHERE content_hash = ?1 AND deleted IS NULL")?;
+        let rows = stmt.query_map(params![content_hash as i64], |row| Ok((row.get(0)?, path_from_bytes(row.get(1)?), row.get(2)?)))?;
+        let items = rows.collect::<rusqlite::Result<_>>()?;
+        Ok(items)
+    }
+    pub fn count_items_by_content_hash(&self, content_hash: u64) -> Result<usize> {
+        Ok(self.conn.query_row("SELECT COUNT(*) FROM items WHERE content_hash = ?1 AND deleted IS NULL", params![content_hash as i64], |row| row.get::<_, i64>(0))? as usize)
+    }
+    /// Returns the id and the fingerprint of the item, None if its attributes were never stored
+    pub fn get_fingerprint_by_path(&self, path: &PathBuf) -> Result<Option<(Id, Fingerprint)>> {
+        Ok(self.conn.query_row("SELECT id, size, modified, content_hash FROM items WHERE path = ?1 AND size IS NOT NULL", params![path_to_bytes(path)], |row| Ok((row.get(0)?, Fingerprint {
+            size: row.get::<_, i64>(1)? as u64,
+            modified: row.get(2)?,
+            content_hash: row.get::<_, Option<i64>>(3)?.map(|h| h as u64)
+        }))).optional()?)
+    }
+    /// Adds a vector after the other chunks of the item and returns its id
+    pub fn insert_embedding(&self, item_id: Id, vector: &[f32; 384], model: &str, range: Option<(usize, usize)>, excerpt: Option<String>) -> Result<Id> {
+        let (start, end) = match range {
+            Some((start, end)) => (Some(start as i64), Some(end as i64)),
+            None => (None, None)
+        };
+        self.conn.execute("INSERT INTO embeddings (item_id, chunk, start_byte, end_byte, excerpt, vector, model) VALUES (?1, (SELECT COUNT(*) FROM embeddings WHERE item_id = ?1), ?2, ?3, ?4, ?5, ?6)", params![item_id, start, end, excerpt, vector_to_blob(vector), model])?;
+        Ok(self.conn.last_insert_rowid() as Id)
+    }
+    pub fn get_embedding_ids_by_item(&self, item_id: Id) -> Result<Vec<Id>> {
+        let mut stmt = self.conn.prepare("SELECT id FROM embeddings WHERE item_id = ?1")?;
+        let rows = stmt.query_map(params![item_id], |row| row.get::<_, Id>(0))?;
+        let ids = rows.collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
+    }
+    /// Returns (embedding id, vector) of the vectors of the item
+    pub fn get_embeddings_by_item(&self, item_id: Id, model: &str) -> Result<Vec<(Id, [f32; 384])>> {
+        let mut stmt = self.conn.prepare("SELECT id, vector FROM embeddings WHERE item_id = ?1 AND model = ?2")?;
+        let rows = stmt.query_map(params![item_id, model], |row| Ok((row.get::<_, Id>(0)?, row.get::<_, Vec<u8>>(1)?)))?;
+        let embeddings = rows.collect::<rusqlite::Result<Vec<_>>>()?.into_iter()
+            .filter_map(|(id, blob)| Some((id, blob_to_vector(&blob)?)))
+            .collect();
+        Ok(embeddings)
+    }
+    pub fn get_vectors_by_item(&self, item_id: Id, model: &str) -> Result<Vec<[f32; 384]>> {
+        let mut stmt = self.conn.prepare("SELECT vector FROM embeddings WHERE item_id = ?1 AND model = ?2 ORDER BY chunk")?;
+        let rows = stmt.query_map(params![item_id, model], |row| row.get::<_, Vec<u8>>(0))?;
+        let vectors = rows.collect::<rusqlite::Result<Vec<_>>>()?.into_iter()
+            .filter_map(|blob| blob_to_vector(&blob))
+            .collect();
+        Ok(vectors)
+    }
+    /// Returns the vectors computed from the content of the item, with their chunk, in order
+    pub fn get_content_embeddings_by_item(&self, item_id: Id, model: &str) -> Result<Vec<(Chunk, [f32; 384])>> {
+        let mut stmt = self.conn.prepare("SELECT chunk, start_byte, end_byte, excerpt, vector FROM embeddings WHERE item_id = ?1 AND model = ?2 AND start_byte IS NOT NULL AND end_byte IS NOT NULL ORDER BY chunk")?;
+        let rows = stmt.query_map(params![item_id, model], |row| Ok((Chunk {
+            index: row.get::<_, i64>(0)? as usize,
+            range: Some((row.get::<_, i64>(1)? as usize, row.get::<_, i64>(2)? as usize)),
+            excerpt: row.get(3)?
+        }, row.get::<_, Vec<u8>>(4)?)))?;
+        let embeddings = rows.collect::<rusqlite::Result<Vec<_>>>()?.into_iter()
+            .filter_map(|(chunk, blob)| Some((chunk, blob_to_vector(&blob)?)))
+            .collect();
+        Ok(embeddings)
+    }
+    pub fn delete_embeddings_by_item(&self, item_id: Id) -> Result<()> {
+        self.conn.execute("DELETE FROM embeddings WHERE item_id = ?1", params![item_id])?;
+        Ok(())
+    }
+    /// Deletes the vectors of the item that don't come from its content
+    pub fn delete_other_embeddings_by_item(&self, item_id: Id) -> Result<()> {
+        self.conn.execute("DELETE FROM embeddings WHERE item_id = ?1 AND (start_byte IS NULL OR end_byte IS NULL)", params![item_id])?;
+        Ok(())
+    }
+    /// Returns (embedding id, item id, vector) of the vectors of the model, only those not in the index file if only_unindexed.
+    /// The vectors of the deleted items aren't returned
+    pub fn get_embeddings(&self, model: &str, only_unindexed: bool) -> Result<Vec<(Id, Id, [f32; 384])>> {
+        let mut stmt = self.conn.prepare("SELECT id, item_id, vector FROM embeddings WHERE model = ?1 AND (indexed = 0 OR ?2 = 0) AND item_id IN (SELECT id FROM items WHERE deleted IS NULL)")?;
+        let rows = stmt.query_map(params![model, only_unindexed], |row| Ok((row.get::<_, Id>(0)?, row.get::<_, Id>(1)?, row.get::<_, Vec<u8>>(2)?)))?;
+        let embeddings = rows.collect::<rusqlite::Result<Vec<_>>>()?.into_iter()
+            .filter_map(|(id, item_id, blob)| Some((id, item_id, blob_to_vector(&blob)?)))
+            .collect();
+        Ok(embeddings)
+    }
+    /// Marks the given embeddings as present in the index file, an other process may have added embeddings missing from the saved index
+    pub fn set_embeddings_indexed(&self, embedding_ids: &[Id]) -> Result<()> {
+        let transaction = self.conn.unchecked_transaction()?;
+        {
+            let mut stmt = transaction.prepare("UPDATE embeddings SET indexed = 1 WHERE id = ?1")?;
+            for embedding_id in embedding_ids {
+                stmt.execute(params![embedding_id])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+    pub fn get_chunk_by_embedding_id(&self, embedding_id: Id) -> Result<Option<(PathBuf, Chunk)>> {
+        Ok(self.conn.query_row("SELECT items.path, embeddings.chunk, embeddings.start_byte, embeddings.end_byte, embeddings.excerpt FROM embeddings JOIN items ON items.id = embeddings.item_id WHERE embeddings.id = ?1", params![embedding_id], |row| {
+            let start: Option<i64> = row.get(2)?;
+            let end: Option<i64> = row.get(3)?;
+            Ok((path_from_bytes(row.get(0)?), Chunk {
+                index: row.get::<_, i64>(1)? as usize,
+                range: start.zip(end).map(|(start, end)| (start as usize, end as usize)),
+                excerpt: row.get(4)?
+            }))
+        }).optional()?)
     }
-    pub fn get_path_by_id(&self, id: Id) -> Option<PathBuf> {
-        match self.conn.query_row("SELECT path FROM items WHERE id = ?1", params![id], |row| row.get::<_, String>(0)).optional().expect("Can't get path from id") {
-            Some(path) => Some(path.into()),
-            None => None
+    /// Returns the description of the index file at path, None if it was saved before the manifests existed
+    pub fn get_index_manifest(&self, path: &Path) -> Result<Option<IndexManifest>> {
+        let manifest = self.conn.query_row("SELECT backend, model, dimension, metric, trees, seed, built, items FROM indexes WHERE path = ?1", params![path_to_bytes(path)], |row| {
+            let backend: String = row.get(0)?;
+            let backend = IndexBackend::from_name(&backend).ok_or_else(|| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, format!("Unknown index backend {}", backend).into()))?;
+            Ok(IndexManifest {
+                backend,
+                model: row.get(1)?,
+                dimension: row.get::<_, i64>(2)? as usize,
+                metric: row.get(3)?,
+                trees: row.get(4)?,
+                seed: row.get::<_, Option<i64>>(5)?.map(|s| s as u64),
+                built: row.get(6)?,
+                roots: Vec::new(),
+                items: row.get::<_, i64>(7)? as usize
+            })
+        }).optional()?;
+        let mut manifest = match manifest {
+            Some(manifest) => manifest,
+            None => return Ok(None)
+        };
+        let mut stmt = self.conn.prepare("SELECT root FROM index_roots WHERE index_path = ?1")?;
+        let rows = stmt.query_map(params![path_to_bytes(path)], |row| Ok(path_from_bytes(row.get(0)?)))?;
+        manifest.roots = rows.collect::<rusqlite::Result<_>>()?;
+        Ok(Some(manifest))
+    }
+    pub fn set_index_manifest(&self, path: &Path, manifest: &IndexManifest) -> Result<()> {
+        let transaction = self.conn.unchecked_transaction()?;
+        transaction.execute("INSERT OR REPLACE INTO indexes (path, backend, model, dimension, metric, trees, seed, built, items) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)", params![path_to_bytes(path), manifest.backend.name(), manifest.model, manifest.dimension as i64, manifest.metric, manifest.trees, manifest.seed.map(|s| s as i64), manifest.built, manifest.items as i64])?;
+        transaction.execute("DELETE FROM index_roots WHERE index_path = ?1", params![path_to_bytes(path)])?;
+        for root in manifest.roots.iter() {
+            transaction.execute("INSERT OR IGNORE INTO index_roots (index_path, root) VALUES (?1, ?2)", params![path_to_bytes(path), path_to_bytes(root)])?;
         }
+        transaction.commit()?;
+        Ok(())
+    }
+    /// Records that the files of directory were all embedded at state by the build of root
+    pub fn insert_processed_directory(&self, root: &Path, directory: &Path, state: EmbeddingState) -> Result<()> {
+        self.conn.execute("INSERT OR REPLACE INTO build_progress (root, directory, state) VALUES (?1, ?2, ?3)", params![path_to_bytes(root), path_to_bytes(directory), state])?;
+        Ok(())
     }
-    pub fn get_state_by_path(&self, path: &PathBuf) -> Option<EmbeddingState> {
-        self.conn.query_row("SELECT state FROM items WHERE path = ?1", params![path.to_string_lossy()], |row| row.get(0)).optional().expect("Can't get state from path")
+    /// Returns the directories recorded by the build of root at state
+    pub fn get_processed_directories(&self, root: &Path, state: EmbeddingState) -> Result<Vec<PathBuf>> {
+        let mut stmt = self.conn.prepare("SELECT directory FROM build_progress WHERE root = ?1 AND state = ?2")?;
+        let rows = stmt.query_map(params![path_to_bytes(root), state], |row| Ok(path_from_bytes(row.get(0)?)))?;
+        let directories = rows.collect::<rusqlite::Result<_>>()?;
+        Ok(directories)
     }
-    pub fn get_item_by_id(&self, id: Id) -> Option<CacheItem> {
-        self.conn.query_row("SELECT path, state FROM items WHERE id = ?1", params![id], |row| Ok(CacheItem {
-            path: row.get::<_, String>(0).expect("Can't get path from id").into(),
-            state: row.get(1).expect("Can't get state from id")
-        })).optional().expect("Can't get item from id")
+    pub fn delete_processed_directories(&self, root: &Path) -> Result<()> {
+        self.conn.execute("DELETE FROM build_progress WHERE root = ?1", params![path_to_bytes(root)])?;
+        Ok(())
     }
-}
\ No newline at end of file
+}
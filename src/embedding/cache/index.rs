@@ -0,0 +1,332 @@
+/// Common interface of the vector indexes, so that the backend can be chosen
+
+use std::{collections::HashMap, fs::File, io::{self, BufReader, BufWriter, Read, Write}, path::PathBuf};
+use super::Id;
+
+mod hnsw;
+mod annoy;
+mod kd_tree;
+pub use hnsw::Hnsw;
+pub use annoy::AnnoyIndex;
+pub use kd_tree::KdTreeIndex;
+
+/// Dimension of the vectors of all the backends
+pub const DIMENSION: usize = 384;
+/// Distance used by all the backends, the squared euclidean distance giving the same order
+pub const METRIC: &str = "euclidean";
+
+pub trait VectorIndex: Send {
+    /// Adds a vector, replacing the previous one if the id is already present
+    fn add(&mut self, id: Id, vector: [f32; 384]);
+    fn remove(&mut self, id: Id);
+    /// Returns the count nearest ids with their squared euclidean distance, nearest first
+    fn search(&self, query: &[f32; 384], count: usize) -> Vec<(f32, Id)>;
+    fn save(&mut self, path: &str) -> io::Result<()>;
+    fn load(path: &str) -> io::Result<Self> where Self: Sized;
+    fn len(&self) -> usize;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexBackend {
+    Hnsw,
+    Annoy,
+    KdTree,
+    /// Exact search, slow on big caches but useful as a reference
+    BruteForce
+}
+impl IndexBackend {
+    pub fn create(&self) -> Box<dyn VectorIndex> {
+        match self {
+            IndexBackend::Hnsw => Box::new(Hnsw::new()),
+            IndexBackend::Annoy => Box::new(AnnoyIndex::new()),
+            IndexBackend::KdTree => Box::new(KdTreeIndex::new()),
+            IndexBackend::BruteForce => Box::new(BruteForce::new())
+        }
+    }
+    /// Name of the backend, as given on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            IndexBackend::Hnsw => "hnsw",
+            IndexBackend::Annoy => "annoy",
+            IndexBackend::KdTree => "kdtree",
+            IndexBackend::BruteForce => "brute-force"
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "hnsw" => IndexBackend::Hnsw,
+            "annoy" => IndexBackend::Annoy,
+            "kdtree" => IndexBackend::KdTree,
+            "brute-force" => IndexBackend::BruteForce,
+            _ => return None
+        })
+    }
+    /// Number of trees built by the backend, for the backends made of trees
+    pub fn trees(&self) -> Option<u32> {
+        match self {
+            IndexBackend::Annoy => Some(annoy::TREES),
+            _ => None
+        }
+    }
+    /// Seed of the random number generator of the backend, for the backends using one
+    pub fn seed(&self) -> Option<u64> {
+        match self {
+            IndexBackend::Hnsw => Some(hnsw::SEED),
+            IndexBackend::Annoy => Some(annoy::SEED),
+            _ => None
+        }
+    }
+    /// Approximate size on disk of an index of count vectors, with the files written next to it
+    pub fn estimated_size(&self, count: usize) -> u64 {
+        match self {
+            IndexBackend::Hnsw => hnsw::estimated_size(count),
+            IndexBackend::Annoy => annoy::estimated_size(count) + vectors_file_size(count),
+            IndexBackend::KdTree | IndexBackend::BruteForce => vectors_file_size(count)
+        }
+    }
+    pub fn load(&self, path: &str) -> io::Result<Box<dyn VectorIndex>> {
+        Ok(match self {
+            IndexBackend::Hnsw => Box::new(Hnsw::load(path)?),
+            IndexBackend::Annoy => Box::new(AnnoyIndex::load(path)?),
+            IndexBackend::KdTree => Box::new(KdTreeIndex::load(path)?),
+            IndexBackend::BruteForce => Box::new(BruteForce::load(path)?)
+        })
+    }
+}
+
+/// Description of an index file, stored in the DB to refuse loading a file built by an other embedder
+#[derive(Debug, Clone)]
+pub struct IndexManifest {
+    pub backend: IndexBackend,
+    pub model: String,
+    pub dimension: usize,
+    pub metric: String,
+    pub trees: Option<u32>,
+    pub seed: Option<u64>,
+    /// Unix timestamp of the last save, in seconds
+    pub built: i64,
+    /// Paths the index was built from
+    pub roots: Vec<PathBuf>,
+    pub items: usize
+}
+impl IndexManifest {
+    pub fn new(backend: IndexBackend, model: &str, built: i64, roots: Vec<PathBuf>, items: usize) -> Self {
+        Self {
+            backend,
+            model: model.to_string(),
+            dimension: DIMENSION,
+            metric: METRIC.to_string(),
+            trees: backend.trees(),
+            seed: backend.seed(),
+            built,
+            roots,
+            items
+        }
+    }
+    /// Returns a description of the differences if the index can't be used with this backend and model
+    pub fn check(&self, backend: IndexBackend, model: &str) -> Option<String> {
+        let mut mismatches = Vec::new();
+        if self.backend != backend {
+            mismatches.push(format!("backend {} instead of {}", self.backend.name(), backend.name()));
+        }
+        if self.model != model {
+            mismatches.push(format!("model {} instead of {}", self.model, model));
+        }
+        if self.dimension != DIMENSION {
+            mismatches.push(format!("dimension {} instead of {}", self.dimension, DIMENSION));
+        }
+        if self.metric != METRIC {
+            mismatches.push(format!("metric {} instead of {}", self.metric, METRIC));
+        }
+        if mismatches.is_empty() {
+            None
+        } else {
+            Some(format!("the index was built with {}", mismatches.join(", ")))
+        }
+    }
+}
+
+pub fn squared_distance(a: &[f32; 384], b: &[f32; 384]) -> f32 {
+    a.iter().zip(b.iter()).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+/// Writes a file next to path then renames it, so that the other processes never read a partially written index
+pub fn write_atomically(path: &str, write: impl FnOnce(&str) -> io::Result<()>) -> io::Result<()> {
+    let temporary_path = format!("{}.tmp", path);
+    write(&temporary_path)?;
+    std::fs::rename(temporary_path, path)
+}
+
+pub fn read_bytes<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+const VECTORS_MAGIC: &[u8; 4] = b"VECS";
+const VECTORS_FORMAT_VERSION: u32 = 1;
+
+/// Size of the file written by save_vectors: the header, then the id and the vector of each entry
+fn vectors_file_size(count: usize) -> u64 {
+    16 + count as u64 * (4 + 4 * DIMENSION as u64)
+}
+
+/// Writes vectors in a file, for the backends that can't give back the vectors they index
+pub fn save_vectors(path: &str, vectors: &HashMap<Id, Box<[f32; 384]>>) -> io::Result<()> {
+    write_atomically(path, |path| {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(VECTORS_MAGIC)?;
+        w.write_all(&VECTORS_FORMAT_VERSION.to_le_bytes())?;
+        w.write_all(&(vectors.len() as u64).to_le_bytes())?;
+        for (id, vector) in vectors.iter() {
+            w.write_all(&id.to_le_bytes())?;
+            for v in vector.iter() {
+                w.write_all(&v.to_le_bytes())?;
+            }
+        }
+        w.flush()
+    })
+}
+
+pub fn load_vectors(path: &str) -> io::Result<HashMap<Id, Box<[f32; 384]>>> {
+    let mut r = BufReader::new(File::open(path)?);
+    if &read_bytes::<4>(&mut r)? != VECTORS_MAGIC || u32::from_le_bytes(read_bytes(&mut r)?) != VECTORS_FORMAT_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a vectors file or unsupported version"));
+    }
+    let len = u64::from_le_bytes(read_bytes(&mut r)?) as usize;
+    let mut vectors = HashMap::with_capacity(len);
+    for _ in 0..len {
+        let id = Id::from_le_bytes(read_bytes(&mut r)?);
+        let mut vector = Box::new([0.; 384]);
+        for v in vector.iter_mut() {
+            *v = f32::from_le_bytes(read_bytes(&mut r)?);
+        }
+        vectors.insert(id, vector);
+    }
+    Ok(vectors)
+}
+
+/// Compares the query to every vector
+pub struct BruteForce {
+    vectors: HashMap<Id, Box<[f32; 384]>>
+}
+impl BruteForce {
+    pub fn new() -> Self {
+        Self {
+            vectors: HashMap::new()
+        }
+    }
+}
+impl VectorIndex for BruteForce {
+    fn add(&mut self, id: Id, vector: [f32; 384]) {
+        self.vectors.insert(id, Box::new(vector));
+    }
+    fn remove(&mut self, id: Id) {
+        self.vectors.remove(&id);
+    }
+    fn search(&self, query: &[f32; 384], count: usize) -> Vec<(f32, Id)> {
+        let mut nearest = self.vectors.iter().map(|(id, v)| (squared_distance(query, v), *id)).collect::<Vec<_>>();
+        nearest.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        nearest.truncate(count);
+        nearest
+    }
+    fn save(&mut self, path: &str) -> io::Result<()> {
+        save_vectors(path, &self.vectors)
+    }
+    fn load(path: &str) -> io::Result<Self> {
+        Ok(Self {
+            vectors: load_vectors(path)?
+        })
+    }
+    fn len(&self) -> usize {
+        self.vectors.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::*;
+
+    const BACKENDS: [IndexBackend; 4] = [IndexBackend::Hnsw, IndexBackend::Annoy, IndexBackend::KdTree, IndexBackend::BruteForce];
+
+    /// xorshift generator, so that a failure can be reproduced
+    struct Rng(u64);
+    impl Rng {
+        fn next_f32(&mut self) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 40) as f32 / (1 << 24) as f32 * 2. - 1.
+        }
+        fn vector(&mut self) -> [f32; 384] {
+            let mut vector = [0.; 384];
+            for v in vector.iter_mut() {
+                *v = self.next_f32();
+            }
+            let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+            for v in vector.iter_mut() {
+                *v /= norm;
+            }
+            vector
+        }
+    }
+
+    fn temporary_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("search-rust-test-{}-{}", std::process::id(), name)).display().to_string()
+    }
+
+    #[test]
+    fn hnsw_recall_against_brute_force() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        let mut hnsw = IndexBackend::Hnsw.create();
+        let mut exact = IndexBackend::BruteForce.create();
+        for id in 0..2000 {
+            let vector = rng.vector();
+            hnsw.add(id, vector);
+            exact.add(id, vector);
+        }
+        // The removed nodes must neither be returned nor disconnect the graph
+        for id in (0..2000).step_by(3) {
+            hnsw.remove(id);
+            exact.remove(id);
+        }
+        assert_eq!(hnsw.len(), exact.len());
+
+        let queries = 100;
+        let mut found = 0;
+        for _ in 0..queries {
+            let query = rng.vector();
+            let expected = exact.search(&query, 10).into_iter().map(|(_, id)| id).collect::<HashSet<_>>();
+            let result = hnsw.search(&query, 10);
+            assert_eq!(result.len(), 10);
+            assert!(result.iter().all(|(_, id)| id % 3 != 0), "a removed vector was returned");
+            found += result.iter().filter(|(_, id)| expected.contains(id)).count();
+        }
+        let recall = found as f32 / (queries * 10) as f32;
+        assert!(recall >= 0.9, "recall@10 is {}", recall);
+    }
+
+    #[test]
+    fn save_and_load_keep_the_vectors() {
+        for backend in BACKENDS {
+            let mut rng = Rng(0x9e3779b97f4a7c15);
+            let mut index = backend.create();
+            for id in 0..200 {
+                index.add(id, rng.vector());
+            }
+            index.remove(5);
+            let path = temporary_path(backend.name());
+            index.save(&path).unwrap();
+            let loaded = backend.load(&path).unwrap();
+            let _ = std::fs::remove_file(&path);
+            let _ = std::fs::remove_file(format!("{}.vectors", path));
+
+            assert_eq!(loaded.len(), 199, "{}", backend.name());
+            for _ in 0..10 {
+                let query = rng.vector();
+                assert_eq!(loaded.search(&query, 10), index.search(&query, 10), "{}", backend.name());
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+/// Adapter of the annoy index of the rannoy crate
+
+use std::{collections::{HashMap, HashSet}, io};
+use rannoy::Rannoy;
+use super::{Id, VectorIndex, squared_distance, save_vectors, load_vectors, write_atomically};
+
+/// Number of trees, higher means more precision but a bigger file
+pub const TREES: u32 = 30;
+/// Seed of the random number generator, so that the same vectors give the same trees
+pub const SEED: u64 = 123;
+
+/// Approximate size of the annoy file of count vectors: a node per vector, and the split nodes of each tree whose leaves hold about as many ids as the dimension
+pub fn estimated_size(count: usize) -> u64 {
+    let node = 16 + 4 * 384;
+    let split_nodes = 2 * count * TREES as usize / 384;
+    ((count + split_nodes) * node) as u64
+}
+
+/// annoy can't be modified once built, so the vectors changed since the last build are compared one by one until the next save
+pub struct AnnoyIndex {
+    annoy: Option<Rannoy>,
+    vectors: HashMap<Id, Box<[f32; 384]>>,
+    /// Ids added, replaced or removed since annoy was built
+    changed: HashSet<Id>
+}
+impl AnnoyIndex {
+    pub fn new() -> Self {
+        Self {
+            annoy: None,
+            vectors: HashMap::new(),
+            changed: HashSet::new()
+        }
+    }
+
+    /// annoy doesn't give back its vectors, so they are kept next to the annoy file
+    fn vectors_path(path: &str) -> String {
+        format!("{}.vectors", path)
+    }
+}
+impl VectorIndex for AnnoyIndex {
+    fn add(&mut self, id: Id, vector: [f32; 384]) {
+        self.vectors.insert(id, Box::new(vector));
+        self.changed.insert(id);
+    }
+    fn remove(&mut self, id: Id) {
+        if self.vectors.remove(&id).is_some() {
+            self.changed.insert(id);
+        }
+    }
+    fn search(&self, query: &[f32; 384], count: usize) -> Vec<(f32, Id)> {
+        let mut nearest = Vec::new();
+        if let Some(ref a) = self.annoy {
+            let result = a.get_nns_by_vector((*query).into(), (count + self.changed.len()).try_into().unwrap(), -1); // search_k can be changed to increase precision but it's slower
+            // annoy gives the euclidean distance
+            for (id, distance) in result.0.into_iter().zip(result.1.into_iter()) {
+                if !self.changed.contains(&id) {
+                    nearest.push((distance * distance, id));
+                }
+            }
+        }
+        for id in self.changed.iter() {
+            if let Some(vector) = self.vectors.get(id) {
+                nearest.push((squared_distance(query, vector), *id));
+            }
+        }
+        nearest.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        nearest.truncate(count);
+        nearest
+    }
+    fn save(&mut self, path: &str) -> io::Result<()> {
+        let annoy = Rannoy::new(384);
+        annoy.set_seed(SEED as _);
+        for (id, vector) in self.vectors.iter() {
+            annoy.add_item(*id, vector.as_ref());
+        }
+        annoy.build(TREES as _);
+        // The vectors are written first, a reader loading the new annoy file with the old vectors would miss the new vectors
+        save_vectors(&Self::vectors_path(path), &self.vectors)?;
+        write_atomically(path, |path| {
+            annoy.save(path);
+            Ok(())
+        })?;
+        self.annoy = Some(annoy);
+        self.changed.clear();
+        Ok(())
+    }
+    fn load(path: &str) -> io::Result<Self> {
+        let vectors = load_vectors(&Self::vectors_path(path))?;
+        let annoy = Rannoy::new(384);
+        annoy.set_seed(SEED as _);
+        annoy.load(path.to_string().into());
+        Ok(Self {
+            annoy: Some(annoy),
+            vectors,
+            changed: HashSet::new()
+        })
+    }
+    fn len(&self) -> usize {
+        self.vectors.len()
+    }
+}
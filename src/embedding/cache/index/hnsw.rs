@@ -0,0 +1,294 @@
+/// Hierarchical navigable small world graph (https://arxiv.org/abs/1603.09320), a vector index supporting inserts and deletes
+
+use std::{collections::{BinaryHeap, HashMap, HashSet}, cmp::Ordering, fs::File, io::{self, BufReader, BufWriter, Write}};
+use super::{Id, VectorIndex, squared_distance, read_bytes, write_atomically};
+
+/// Number of neighbors of a node in the upper levels
+const M: usize = 16;
+/// Number of neighbors of a node in the level 0
+const M0: usize = 32;
+const EF_CONSTRUCTION: usize = 100;
+const EF_SEARCH: usize = 128;
+/// Seed of the random number generator drawing the levels of the nodes
+pub const SEED: u64 = 123;
+const MAGIC: &[u8; 4] = b"HNSW";
+const FORMAT_VERSION: u32 = 1;
+
+/// Approximate size of the file of count nodes, their neighbors being full.
+/// A node has the level 0, and one node in M - 1 has an upper level
+pub fn estimated_size(count: usize) -> u64 {
+    // id, deleted, vector, number of levels, then the size and the neighbors of each level
+    let node = 4 + 1 + 4 * 384 + 4 + (4 + 4 * M0) + (4 + 4 * M) / (M - 1);
+    32 + (count * node) as u64
+}
+
+/// (squared distance, node index), ordered by distance
+#[derive(Clone, Copy, PartialEq)]
+struct Candidate(f32, usize);
+impl Eq for Candidate {}
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+struct Node {
+    id: Id,
+    vector: Box<[f32; 384]>,
+    /// Neighbors of the node in each level it belongs to
+    neighbors: Vec<Vec<usize>>,
+    /// Deleted nodes are kept to navigate the graph but never returned
+    deleted: bool
+}
+
+pub struct Hnsw {
+    nodes: Vec<Node>,
+    indexes: HashMap<Id, usize>,
+    entry: Option<usize>,
+    deleted: usize,
+    rng: u64
+}
+impl Hnsw {
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            indexes: HashMap::new(),
+            entry: None,
+            deleted: 0,
+            rng: SEED
+        }
+    }
+
+    /// Draws the top level of a new node, with an exponentially decreasing probability
+    fn random_level(&mut self) -> usize {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let uniform = ((self.rng >> 11) as f64 + 1.) / ((1u64 << 53) as f64 + 1.);
+        (-uniform.ln() / (M as f64).ln()).floor() as usize
+    }
+
+    fn top_level(&self) -> usize {
+        match self.entry {
+            Some(e) => self.nodes[e].neighbors.len() - 1,
+            None => 0
+        }
+    }
+
+    /// Returns the ef nodes nearest to the query found in a level, nearest first
+    fn search_level(&self, query: &[f32; 384], entries: &[usize], ef: usize, level: usize) -> Vec<Candidate> {
+        let mut visited: HashSet<usize> = entries.iter().copied().collect();
+        // min heap of the nodes to explore
+        let mut candidates: BinaryHeap<std::cmp::Reverse<Candidate>> = BinaryHeap::new();
+        // max heap of the best nodes found
+        let mut found: BinaryHeap<Candidate> = BinaryHeap::new();
+        for &e in entries {
+            let c = Candidate(squared_distance(query, &self.nodes[e].vector), e);
+            candidates.push(std::cmp::Reverse(c));
+            found.push(c);
+        }
+        while let Some(std::cmp::Reverse(current)) = candidates.pop() {
+            if let Some(worst) = found.peek() {
+                if current.0 > worst.0 && found.len() >= ef {
+                    break;
+                }
+            }
+            for &n in self.nodes[current.1].neighbors[level].iter() {
+                if !visited.insert(n) {
+                    continue;
+                }
+                let c = Candidate(squared_distance(query, &self.nodes[n].vector), n);
+                if found.len() < ef || c.0 < found.peek().map_or(f32::MAX, |w| w.0) {
+                    candidates.push(std::cmp::Reverse(c));
+                    found.push(c);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        found.into_sorted_vec()
+    }
+
+    /// Keeps the max nearest neighbors of a node
+    fn prune(&mut self, node: usize, level: usize, max: usize) {
+        if self.nodes[node].neighbors[level].len() <= max {
+            return;
+        }
+        let vector = self.nodes[node].vector.clone();
+        let mut neighbors = self.nodes[node].neighbors[level].iter()
+            .map(|&n| Candidate(squared_distance(&vector, &self.nodes[n].vector), n))
+            .collect::<Vec<_>>();
+        neighbors.sort();
+        neighbors.truncate(max);
+        self.nodes[node].neighbors[level] = neighbors.into_iter().map(|c| c.1).collect();
+    }
+
+    fn compact(&mut self) {
+        let nodes = std::mem::take(&mut self.nodes);
+        self.indexes.clear();
+        self.entry = None;
+        self.deleted = 0;
+        for node in nodes.into_iter().filter(|n| !n.deleted) {
+            self.add(node.id, *node.vector);
+        }
+    }
+}
+
+impl VectorIndex for Hnsw {
+    fn add(&mut self, id: Id, vector: [f32; 384]) {
+        self.remove(id);
+        let level = self.random_level();
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            id,
+            vector: Box::new(vector),
+            neighbors: vec![Vec::new(); level + 1],
+            deleted: false
+        });
+        self.indexes.insert(id, index);
+
+        let entry = match self.entry {
+            None => {
+                self.entry = Some(index);
+                return;
+            },
+            Some(e) => e
+        };
+        let top_level = self.top_level();
+
+        // Go down greedily to the level of the new node
+        let mut entries = vec![entry];
+        for l in (level + 1..=top_level).rev() {
+            entries = vec![self.search_level(&vector, &entries, 1, l)[0].1];
+        }
+
+        for l in (0..=level.min(top_level)).rev() {
+            let found = self.search_level(&vector, &entries, EF_CONSTRUCTION, l);
+            let max = if l == 0 { M0 } else { M };
+            let neighbors = found.iter().take(max).map(|c| c.1).collect::<Vec<_>>();
+            for &n in neighbors.iter() {
+                self.nodes[n].neighbors[l].push(index);
+                self.prune(n, l, max);
+            }
+            self.nodes[index].neighbors[l] = neighbors;
+            entries = found.into_iter().map(|c| c.1).collect();
+        }
+
+        if level > top_level {
+            self.entry = Some(index);
+        }
+    }
+
+    fn remove(&mut self, id: Id) {
+        if let Some(index) = self.indexes.remove(&id) {
+            self.nodes[index].deleted = true;
+            self.deleted += 1;
+            // Rebuild the graph once the deleted nodes are the majority, as they slow down the search
+            if self.deleted > self.nodes.len() / 2 {
+                self.compact();
+            }
+        }
+    }
+
+    fn search(&self, query: &[f32; 384], count: usize) -> Vec<(f32, Id)> {
+        let entry = match self.entry {
+            None => return Vec::new(),
+            Some(e) => e
+        };
+        let mut entries = vec![entry];
+        for l in (1..=self.top_level()).rev() {
+            entries = vec![self.search_level(query, &entries, 1, l)[0].1];
+        }
+        self.search_level(query, &entries, EF_SEARCH.max(count), 0).into_iter()
+            .filter(|c| !self.nodes[c.1].deleted)
+            .take(count)
+            .map(|c| (c.0, self.nodes[c.1].id))
+            .collect()
+    }
+
+    fn save(&mut self, path: &str) -> io::Result<()> {
+        write_atomically(path, |path| {
+            let mut w = BufWriter::new(File::create(path)?);
+            w.write_all(MAGIC)?;
+            w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+            w.write_all(&(self.nodes.len() as u64).to_le_bytes())?;
+            w.write_all(&self.entry.map_or(-1, |e| e as i64).to_le_bytes())?;
+            w.write_all(&self.rng.to_le_bytes())?;
+            for node in self.nodes.iter() {
+                w.write_all(&node.id.to_le_bytes())?;
+                w.write_all(&[node.deleted as u8])?;
+                for v in node.vector.iter() {
+                    w.write_all(&v.to_le_bytes())?;
+                }
+                w.write_all(&(node.neighbors.len() as u32).to_le_bytes())?;
+                for neighbors in node.neighbors.iter() {
+                    w.write_all(&(neighbors.len() as u32).to_le_bytes())?;
+                    for &n in neighbors {
+                        w.write_all(&(n as u32).to_le_bytes())?;
+                    }
+                }
+            }
+            w.flush()
+        })
+    }
+
+    fn load(path: &str) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        let mut r = BufReader::new(File::open(path)?);
+        if &read_bytes::<4>(&mut r)? != MAGIC || u32::from_le_bytes(read_bytes(&mut r)?) != FORMAT_VERSION {
+            return Err(invalid("Not an index file or unsupported version"));
+        }
+        let len = u64::from_le_bytes(read_bytes(&mut r)?) as usize;
+        let entry = i64::from_le_bytes(read_bytes(&mut r)?);
+        let mut hnsw = Self::new();
+        hnsw.rng = u64::from_le_bytes(read_bytes(&mut r)?);
+        hnsw.entry = if entry < 0 { None } else { Some(entry as usize) };
+        for index in 0..len {
+            let id = Id::from_le_bytes(read_bytes(&mut r)?);
+            let deleted = read_bytes::<1>(&mut r)?[0] != 0;
+            let mut vector = Box::new([0.; 384]);
+            for v in vector.iter_mut() {
+                *v = f32::from_le_bytes(read_bytes(&mut r)?);
+            }
+            let levels = u32::from_le_bytes(read_bytes(&mut r)?) as usize;
+            let mut neighbors = Vec::with_capacity(levels);
+            for _ in 0..levels {
+                let count = u32::from_le_bytes(read_bytes(&mut r)?) as usize;
+                let mut level = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let n = u32::from_le_bytes(read_bytes(&mut r)?) as usize;
+                    if n >= len {
+                        return Err(invalid("Neighbor out of bounds"));
+                    }
+                    level.push(n);
+                }
+                neighbors.push(level);
+            }
+            if levels == 0 {
+                return Err(invalid("Node without level"));
+            }
+            if deleted {
+                hnsw.deleted += 1;
+            } else {
+                hnsw.indexes.insert(id, index);
+            }
+            hnsw.nodes.push(Node { id, vector, neighbors, deleted });
+        }
+        if hnsw.entry.map_or(len > 0, |e| e >= len) {
+            return Err(invalid("Entry point out of bounds"));
+        }
+        Ok(hnsw)
+    }
+
+    fn len(&self) -> usize {
+        self.indexes.len()
+    }
+}
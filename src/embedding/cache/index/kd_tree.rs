@@ -0,0 +1,69 @@
+/// Adapter of the kd-tree of the kdtree crate
+
+use std::{collections::{HashMap, HashSet}, io, sync::Arc};
+use kdtree::{KdTree, distance::squared_euclidean};
+use super::{Id, VectorIndex, save_vectors, load_vectors};
+
+/// The kd-tree can't remove a point safely, so removed and replaced vectors stay in it and are filtered out of the results
+pub struct KdTreeIndex {
+    tree: KdTree<f32, Id, Arc<[f32]>>,
+    vectors: HashMap<Id, Box<[f32; 384]>>
+}
+impl KdTreeIndex {
+    pub fn new() -> Self {
+        Self {
+            tree: KdTree::new(384),
+            vectors: HashMap::new()
+        }
+    }
+
+    /// Number of vectors of the tree that aren't valid anymore
+    fn stale(&self) -> usize {
+        self.tree.size() - self.vectors.len()
+    }
+
+    fn rebuild(&mut self) {
+        self.tree = KdTree::new(384);
+        for (id, vector) in self.vectors.iter() {
+            self.tree.add(Arc::new(**vector) as Arc<[f32]>, *id).expect("Can't add item to kd-tree");
+        }
+    }
+}
+impl VectorIndex for KdTreeIndex {
+    fn add(&mut self, id: Id, vector: [f32; 384]) {
+        self.remove(id);
+        self.tree.add(Arc::new(vector) as Arc<[f32]>, id).expect("Can't add item to kd-tree");
+        self.vectors.insert(id, Box::new(vector));
+    }
+    fn remove(&mut self, id: Id) {
+        if self.vectors.remove(&id).is_some() && self.stale() > self.vectors.len() {
+            self.rebuild();
+        }
+    }
+    fn search(&self, query: &[f32; 384], count: usize) -> Vec<(f32, Id)> {
+        let nearest = self.tree.nearest(query, count + self.stale(), &squared_euclidean).expect("Can't get nearest in kd-tree");
+        // A result is valid if its vector is still the one of its id
+        let mut seen = HashSet::new();
+        let mut nearest = nearest.into_iter()
+            .filter(|(distance, id)| self.vectors.get(*id).map_or(false, |v| squared_euclidean(query, &v[..]) == *distance))
+            .filter(|(_, id)| seen.insert(**id))
+            .map(|(distance, id)| (distance, *id))
+            .collect::<Vec<_>>();
+        nearest.truncate(count);
+        nearest
+    }
+    fn save(&mut self, path: &str) -> io::Result<()> {
+        save_vectors(path, &self.vectors)
+    }
+    fn load(path: &str) -> io::Result<Self> {
+        let mut index = Self {
+            tree: KdTree::new(384),
+            vectors: load_vectors(path)?
+        };
+        index.rebuild();
+        Ok(index)
+    }
+    fn len(&self) -> usize {
+        self.vectors.len()
+    }
+}
@@ -0,0 +1,63 @@
+/// Advisory lock file, so that only one process at a time writes the database and the index file
+
+use std::{fs::{File, OpenOptions}, io::{self, Write}, path::PathBuf};
+use fs2::FileExt;
+
+/// The lock is an OS lock on the file, released by the system when its process dies, so a dead holder never blocks the others.
+/// The file itself stays, it contains the id of the last process holding the lock
+#[derive(Debug)]
+pub struct WriteLock {
+    file: File
+}
+impl WriteLock {
+    /// Returns None if another process holds the lock
+    pub fn try_acquire(path: PathBuf) -> io::Result<Option<Self>> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(&path)?;
+        match file.try_lock_exclusive() {
+            Ok(()) => {
+                file.set_len(0)?;
+                write!(file, "{}", std::process::id())?;
+                Ok(Some(Self { file }))
+            },
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+}
+impl Drop for WriteLock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock too
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temporary_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("search-rust-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn held_lock_is_refused_until_dropped() {
+        let path = temporary_path("held.lock");
+        let lock = WriteLock::try_acquire(path.clone()).unwrap().expect("the lock is free");
+        assert!(WriteLock::try_acquire(path.clone()).unwrap().is_none());
+        drop(lock);
+        assert!(WriteLock::try_acquire(path.clone()).unwrap().is_some());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn lock_file_of_a_dead_process_is_taken_over() {
+        let path = temporary_path("dead.lock");
+        // Left by a process that died, the system released its lock
+        fs::write(&path, "4194305").unwrap();
+        let lock = WriteLock::try_acquire(path.clone()).unwrap().expect("the lock of a dead process is taken over");
+        assert_eq!(fs::read_to_string(&path).unwrap(), std::process::id().to_string());
+        drop(lock);
+        fs::remove_file(path).unwrap();
+    }
+}
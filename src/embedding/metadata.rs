@@ -0,0 +1,110 @@
+/// Extraction of the metadata embedded in media files (EXIF for images, tags for audio and video)
+
+use std::{path::PathBuf, fs::File, io::BufReader};
+use exif::{In, Tag};
+use symphonia::core::{io::MediaSourceStream, probe::Hint, formats::FormatOptions, meta::{MetadataOptions, MetadataRevision, StandardTagKey}};
+use crate::error::Result;
+
+const IMAGE_EXTENSIONS: [&str; 10] = ["jpg", "jpeg", "png", "tif", "tiff", "webp", "heic", "heif", "avif", "dng"];
+const AUDIO_VIDEO_EXTENSIONS: [&str; 14] = ["mp3", "flac", "ogg", "oga", "opus", "wav", "m4a", "aac", "mp4", "m4v", "mov", "mkv", "webm", "caf"];
+
+fn get_exif_prompts(path: &PathBuf) -> Result<Vec<String>> {
+    let mut prompts = Vec::new();
+    let mut reader = BufReader::new(File::open(path)?);
+    let exif = exif::Reader::new().read_from_container(&mut reader)?;
+
+    let field = |tag: Tag| -> Option<String> {
+        let value = exif.get_field(tag, In::PRIMARY)?.display_value().with_unit(&exif).to_string();
+        let value = value.trim_matches(|c: char| c == '"' || c.is_whitespace()).to_string();
+        if value.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    };
+
+    let camera = [field(Tag::Make), field(Tag::Model)].into_iter().flatten().collect::<Vec<_>>();
+    if !camera.is_empty() {
+        prompts.push("camera: ".to_string() + &camera.join(" "));
+    }
+    if let Some(date) = field(Tag::DateTimeOriginal).or_else(|| field(Tag::DateTime)) {
+        prompts.push("date: ".to_string() + &date);
+    }
+    if let Some(place) = field(Tag::GPSAreaInformation) {
+        prompts.push("place: ".to_string() + &place);
+    }
+    if let Some(description) = field(Tag::ImageDescription) {
+        prompts.push("description: ".to_string() + &description);
+    }
+    if let Some(comment) = field(Tag::UserComment) {
+        prompts.push("comment: ".to_string() + &comment);
+    }
+
+    Ok(prompts)
+}
+
+fn push_tag_prompts(revision: &MetadataRevision, prompts: &mut Vec<String>) {
+    for tag in revision.tags() {
+        let label = match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => "title",
+            Some(StandardTagKey::Artist) => "artist",
+            Some(StandardTagKey::AlbumArtist) => "album artist",
+            Some(StandardTagKey::Album) => "album",
+            Some(StandardTagKey::Composer) => "composer",
+            Some(StandardTagKey::Genre) => "genre",
+            Some(StandardTagKey::Date) | Some(StandardTagKey::ReleaseDate) => "date",
+            Some(StandardTagKey::Description) => "description",
+            Some(StandardTagKey::Comment) => "comment",
+            Some(StandardTagKey::TvShowTitle) => "show",
+            Some(StandardTagKey::TvEpisodeTitle) => "episode",
+            _ => continue
+        };
+        let value = tag.value.to_string();
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        let prompt = format!("{}: {}", label, value);
+        if !prompts.contains(&prompt) {
+            prompts.push(prompt);
+        }
+    }
+}
+
+fn get_tags_prompts(path: &PathBuf) -> Result<Vec<String>> {
+    let mut prompts = Vec::new();
+    let mss = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(e) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(e);
+    }
+    let mut probed = symphonia::default::get_probe().format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default())?;
+
+    // Tags found before the container (ID3v2 in front of a mp3 for example)
+    if let Some(mut metadata) = probed.metadata.get() {
+        if let Some(revision) = metadata.skip_to_latest() {
+            push_tag_prompts(revision, &mut prompts);
+        }
+    }
+    // Tags of the container itself (Vorbis comments, FLAC, MP4 and MKV metadata)
+    if let Some(revision) = probed.format.metadata().skip_to_latest() {
+        push_tag_prompts(revision, &mut prompts);
+    }
+
+    Ok(prompts)
+}
+
+/// Returns the prompts describing the metadata of an image, audio or video file, or nothing if the file isn't a media
+pub fn get_media_metadata_prompts(path: &PathBuf) -> Result<Vec<String>> {
+    let extension = match path.extension().and_then(|e| e.to_str()) {
+        None => return Ok(Vec::new()),
+        Some(e) => e.to_lowercase()
+    };
+    if IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+        get_exif_prompts(path)
+    } else if AUDIO_VIDEO_EXTENSIONS.contains(&extension.as_str()) {
+        get_tags_prompts(path)
+    } else {
+        Ok(Vec::new())
+    }
+}
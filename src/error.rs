@@ -2,25 +2,35 @@ use std::sync::{PoisonError, Arc};
 
 #[derive(Debug)]
 pub enum Error {
-    //Rusqlite(rusqlite::Error),
+    Rusqlite(rusqlite::Error),
     Io(std::io::Error),
     RustBert(rust_bert::RustBertError),
     ScanDir(scan_dir::Error),
     ScanDirVec(Vec<scan_dir::Error>),
+    Exif(exif::Error),
+    Symphonia(symphonia::core::errors::Error),
     LockPoison(String),
     CliArgs(String),
     CannotConvertOsStr,
+    /// The database was written by a newer version of the program, with this schema version
+    UnsupportedSchemaVersion(u32),
+    /// An other process is writing the database, the path is the one of its lock file
+    DatabaseLocked(String),
+    /// The index file was built by an other embedder, the message describes the differences
+    IndexMismatch(String),
+    /// An item expected in the database isn't there
+    ItemNotFound(std::path::PathBuf),
     CannotGetFileStem,
     NotImplementedYet,
     Boxed(Box<Self>),
     Arced(Arc<Self>),
 }
 
-//impl From<rusqlite::Error> for Error {
-//    fn from(value: rusqlite::Error) -> Self {
-//        Self::Rusqlite(value)
-//    }²
-//}
+impl From<rusqlite::Error> for Error {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::Rusqlite(value)
+    }
+}
 
 impl From<std::io::Error> for Error {
     fn from(value: std::io::Error) -> Self {
@@ -46,6 +56,18 @@ impl From<Vec<scan_dir::Error>> for Error {
     }
 }
 
+impl From<exif::Error> for Error {
+    fn from(value: exif::Error) -> Self {
+        Self::Exif(value)
+    }
+}
+
+impl From<symphonia::core::errors::Error> for Error {
+    fn from(value: symphonia::core::errors::Error) -> Self {
+        Self::Symphonia(value)
+    }
+}
+
 impl<T> From<PoisonError<T>> for Error {
     fn from(value: PoisonError<T>) -> Self {
         Self::LockPoison(value.to_string())
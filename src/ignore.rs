@@ -0,0 +1,303 @@
+/// Gitignore-style rules deciding which items are skipped when walking directories
+
+use std::{path::{Path, PathBuf, Component}, sync::Arc, fs::read_to_string};
+
+/// Ignore files read in each directory, the later ones taking precedence
+const IGNORE_FILES: [&str; 3] = [".gitignore", ".ignore", ".searchignore"];
+/// Always ignored unless the rules are disabled, version control directories are never listed in their own ignore files
+const DEFAULT_PATTERNS: [&str; 3] = [".git/", ".hg/", ".svn/"];
+
+/// Path of the global exclude list, with one glob per line like a .gitignore
+fn global_ignore_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("search-rust").join("ignore"))
+}
+
+/// Returns true if the glob matches the whole text. `*` and `?` don't match `/`, `**` matches any number of directories
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    // The suffixes already known not to match, so that patterns like `*a*a*a*b` don't backtrack exponentially
+    let mut failed = vec![false; (pattern.len() + 1) * (text.len() + 1)];
+    glob_match_suffix(pattern, text, &mut failed, text.len() + 1)
+}
+
+/// pattern and text are suffixes of the ones given to glob_match, failed is indexed by their lengths
+fn glob_match_suffix(pattern: &[u8], text: &[u8], failed: &mut [bool], width: usize) -> bool {
+    let key = pattern.len() * width + text.len();
+    if failed[key] {
+        return false;
+    }
+    let mut matches = |pattern: &[u8], text: &[u8]| glob_match_suffix(pattern, text, failed, width);
+    let matched = match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            // A trailing "**" matches everything inside
+            if rest.is_empty() {
+                return true;
+            }
+            let rest = rest.strip_prefix(b"/").unwrap_or(rest);
+            (0..=text.len()).any(|i| (i == 0 || text[i - 1] == b'/') && matches(rest, &text[i..]))
+        },
+        Some(b'*') => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| matches(&pattern[1..], &text[i..])),
+        Some(b'?') => text.first().map_or(false, |&c| c != b'/') && matches(&pattern[1..], &text[1..]),
+        Some(b'[') => match class_end(pattern) {
+            Some(end) => text.first().map_or(false, |&c| c != b'/' && class_match(&pattern[1..end], c)) && matches(&pattern[end + 1..], &text[1..]),
+            // An unclosed bracket is a literal
+            None => text.first() == Some(&b'[') && matches(&pattern[1..], &text[1..])
+        },
+        Some(b'\\') if pattern.len() > 1 => text.first() == Some(&pattern[1]) && matches(&pattern[2..], &text[1..]),
+        Some(c) => text.first() == Some(c) && matches(&pattern[1..], &text[1..])
+    };
+    if !matched {
+        failed[key] = true;
+    }
+    matched
+}
+
+/// Index of the bracket closing the class starting the pattern, a `]` right after the opening bracket is part of the class
+fn class_end(pattern: &[u8]) -> Option<usize> {
+    let mut i = 1;
+    if matches!(pattern.get(i), Some(b'!') | Some(b'^')) {
+        i += 1;
+    }
+    if pattern.get(i) == Some(&b']') {
+        i += 1;
+    }
+    pattern[i..].iter().position(|&c| c == b']').map(|position| i + position)
+}
+
+/// class is the content of the brackets, like `!a-z0`
+fn class_match(class: &[u8], c: u8) -> bool {
+    let (negated, class) = match class.first() {
+        Some(b'!') | Some(b'^') => (true, &class[1..]),
+        _ => (false, class)
+    };
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            matched |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+    matched != negated
+}
+
+#[derive(Debug)]
+struct Pattern {
+    glob: Vec<u8>,
+    /// Starts with `!`, the items matching it aren't ignored
+    negated: bool,
+    /// Ends with `/`, only matches directories
+    directory_only: bool,
+    /// Contains a `/` before its end, so it matches the path relative to the directory of the ignore file instead of the name
+    anchored: bool
+}
+impl Pattern {
+    /// Returns None for the empty lines and the comments
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            None => (false, line)
+        };
+        let (directory_only, line) = match line.strip_suffix('/') {
+            Some(line) => (true, line),
+            None => (false, line)
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        // "\#" and "\!" escape the first character
+        let line = match line.strip_prefix('\\') {
+            Some(rest) if rest.starts_with(['#', '!']) => rest,
+            _ => line
+        };
+        if line.is_empty() {
+            return None;
+        }
+        Some(Self {
+            glob: line.as_bytes().to_vec(),
+            negated,
+            directory_only,
+            anchored
+        })
+    }
+}
+
+/// The rules of a directory, linked to the rules of its parent
+#[derive(Debug)]
+pub struct Ignore {
+    /// Directory of the ignore files, the anchored patterns are relative to it
+    base: PathBuf,
+    patterns: Vec<Pattern>,
+    parent: Option<Arc<Ignore>>,
+    enabled: bool
+}
+impl Ignore {
+    /// Rules applying to the children of dir: the global exclude list and the ignore files of dir and of its parents.
+    /// If not enabled, nothing is ignored
+    pub fn for_dir(dir: &Path, enabled: bool) -> Arc<Self> {
+        let mut patterns = Vec::new();
+        if enabled {
+            patterns.extend(DEFAULT_PATTERNS.iter().filter_map(|line| Pattern::parse(line)));
+            if let Some(content) = global_ignore_path().and_then(|path| read_to_string(path).ok()) {
+                patterns.extend(content.lines().filter_map(Pattern::parse));
+            }
+        }
+        let mut rules = Arc::new(Self {
+            base: PathBuf::from("/"),
+            patterns,
+            parent: None,
+            enabled
+        });
+        let mut ancestors = dir.ancestors().collect::<Vec<_>>();
+        ancestors.reverse();
+        for ancestor in ancestors {
+            rules = rules.child(ancestor);
+        }
+        rules
+    }
+
+    /// Rules applying to the children of dir, a child of the directory of these rules
+    pub fn child(self: &Arc<Self>, dir: &Path) -> Arc<Self> {
+        if !self.enabled {
+            return self.clone();
+        }
+        let patterns = IGNORE_FILES.iter()
+            .filter_map(|name| read_to_string(dir.join(name)).ok())
+            .flat_map(|content| content.lines().filter_map(Pattern::parse).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        // Most directories have no ignore file and share the rules of their parent
+        if patterns.is_empty() {
+            return self.clone();
+        }
+        Arc::new(Self {
+            base: dir.to_path_buf(),
+            patterns,
+            parent: Some(self.clone()),
+            enabled: true
+        })
+    }
+
+    /// The rules of the deepest directories are checked first, and the last matching pattern of a directory decides
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut rules = Some(self);
+        while let Some(r) = rules {
+            if let Some(ignored) = r.matches(path, is_dir) {
+                return ignored;
+            }
+            rules = r.parent.as_deref();
+        }
+        false
+    }
+
+    fn matches(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let name = path.file_name()?.as_encoded_bytes();
+        // Components joined by "/", so that the globs are the same on every system
+        let relative = path.strip_prefix(&self.base).ok().map(|relative| {
+            relative.components()
+                .filter_map(|c| match c {
+                    Component::Normal(c) => Some(c.as_encoded_bytes()),
+                    _ => None
+                })
+                .collect::<Vec<_>>()
+                .join(&b'/')
+        });
+        for pattern in self.patterns.iter().rev() {
+            if pattern.directory_only && !is_dir {
+                continue;
+            }
+            let matched = if pattern.anchored {
+                relative.as_ref().map_or(false, |relative| glob_match(&pattern.glob, relative))
+            } else {
+                glob_match(&pattern.glob, name)
+            };
+            if matched {
+                return Some(!pattern.negated);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rules of the ignore file of /base, or of /base/child with the rules of /base as parent
+    fn rules(lines: &[&str], child_lines: &[&str]) -> Ignore {
+        let parent = Arc::new(Ignore {
+            base: PathBuf::from("/base"),
+            patterns: lines.iter().filter_map(|line| Pattern::parse(line)).collect(),
+            parent: None,
+            enabled: true
+        });
+        Ignore {
+            base: PathBuf::from("/base/child"),
+            patterns: child_lines.iter().filter_map(|line| Pattern::parse(line)).collect(),
+            parent: Some(parent),
+            enabled: true
+        }
+    }
+
+    #[test]
+    fn patterns_match_like_gitignore() {
+        // (lines of /base, lines of /base/child, path, is_dir, ignored)
+        let cases: &[(&[&str], &[&str], &str, bool, bool)] = &[
+            // Without a slash the name is matched at any depth
+            (&["*.log"], &[], "/base/a/b.log", false, true),
+            (&["*.log"], &[], "/base/a/b.txt", false, false),
+            (&["build"], &[], "/base/a/build", true, true),
+            // With a slash the path relative to the ignore file is matched
+            (&["/build"], &[], "/base/build", true, true),
+            (&["/build"], &[], "/base/a/build", true, false),
+            (&["doc/*.txt"], &[], "/base/doc/a.txt", false, true),
+            (&["doc/*.txt"], &[], "/base/a/doc/a.txt", false, false),
+            (&["doc/*.txt"], &[], "/base/doc/a/b.txt", false, false),
+            // A trailing slash only matches directories
+            (&["target/"], &[], "/base/target", true, true),
+            (&["target/"], &[], "/base/target", false, false),
+            // The last matching pattern decides
+            (&["*.log", "!keep.log"], &[], "/base/keep.log", false, false),
+            (&["*.log", "!keep.log"], &[], "/base/other.log", false, true),
+            (&["!keep.log", "*.log"], &[], "/base/keep.log", false, true),
+            // The rules of the deepest directory are checked first
+            (&["*.log"], &["!keep.log"], "/base/child/keep.log", false, false),
+            (&["!keep.log"], &["*.log"], "/base/child/keep.log", false, true),
+            // "**" matches any number of directories
+            (&["**/cache"], &[], "/base/cache", true, true),
+            (&["**/cache"], &[], "/base/a/b/cache", true, true),
+            (&["a/**/b"], &[], "/base/a/b", true, true),
+            (&["a/**/b"], &[], "/base/a/x/y/b", true, true),
+            (&["a/**"], &[], "/base/a/x/y", false, true),
+            (&["a/**"], &[], "/base/b/x", false, false),
+            // "*" and "?" don't match "/"
+            (&["a/*"], &[], "/base/a/x/y", false, false),
+            (&["a?b"], &[], "/base/acb", false, true),
+            (&["[a-c]x"], &[], "/base/bx", false, true),
+            (&["[!a-c]x"], &[], "/base/bx", false, false),
+            // "\#" and "\!" escape the first character, a "#" starts a comment
+            (&["\\#notes"], &[], "/base/#notes", false, true),
+            (&["#notes"], &[], "/base/#notes", false, false),
+            (&["\\!important"], &[], "/base/!important", false, true)
+        ];
+        for (lines, child_lines, path, is_dir, ignored) in cases {
+            assert_eq!(rules(lines, child_lines).is_ignored(Path::new(path), *is_dir), *ignored, "{:?} {:?} on {}", lines, child_lines, path);
+        }
+    }
+
+    #[test]
+    fn stars_dont_backtrack_exponentially() {
+        let text = "a".repeat(200);
+        assert!(!glob_match(b"*a*a*a*a*a*a*a*a*a*a*b", text.as_bytes()));
+        assert!(!glob_match(b"**/a**/a**/a**/a**/b", text.as_bytes()));
+        assert!(glob_match(b"*a*a*a*a*a*a*a*a*a*a", text.as_bytes()));
+    }
+}
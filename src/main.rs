@@ -4,31 +4,44 @@ mod ui;
 mod rank;
 mod build;
 mod embedding;
-use annoy_rs::AnnoyIndex;
-use annoy_rs::AnnoyIndexSearchApi;
-use embedding::Embedder;
+mod ignore;
+mod walk;
+use embedding::{Embedder, IndexBackend, ChunkAggregation};
 use error::Error;
 use error::Result;
 use ui::UI;
 use ui::visual_pack::VisualPack;
+use walk::WalkOptions;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let embedder = Embedder::new(None, None).await;
-    let annoy = AnnoyIndex::load(384, "cache.ann", annoy_rs::IndexType::Euclidean).unwrap();
-    println!("{}", annoy.size);
-    let result = annoy.get_nearest(embedder.embed(&["test"]).await[0].as_ref(), 18, 10, true);
-    println!("{:#?}", result.id_list);
-    println!("{:#?}", result.distance_list);
+    let mut embedder = Embedder::new(Some("cache.db".to_string()), Some("cache.ann".to_string()), IndexBackend::Hnsw).await?;
+    println!("{}", embedder.cache.lock().await.len());
+    let result = embedder.nearest("test", 10).await?;
+    println!("{:#?}", result);
     Ok(())
 }
 
+/// Command given by the flags, run once all of them are read so that their order doesn't matter
+enum Command {
+    Reindex,
+    Prune,
+    /// Level and target of the build
+    Build(embedding::EmbeddingState, String)
+}
+
 //#[tokio::main]
 async fn mainr() -> Result<()> {
     let mut cache_path = None;
     let mut db_path = None;
     let mut target_file = None;
     let mut vp = VisualPack::ExtendedUnicode;
+    let mut backend = IndexBackend::Hnsw;
+    let mut walk_options = WalkOptions::default();
+    let mut aggregation = ChunkAggregation::default();
+    let mut resume = false;
+    let mut dry_run = false;
+    let mut command = None;
 
     let args: Vec<String> = env::args().collect();
     for (i, arg) in env::args().enumerate() {
@@ -74,6 +87,63 @@ async fn mainr() -> Result<()> {
                     return Err(Error::CliArgs("Bad args : --style".to_string()))
                 }
             }
+            "--index" => {
+                if i + 1 < args.len() {
+                    backend = match IndexBackend::from_name(&args[i + 1]) {
+                        Some(backend) => backend,
+                        None => {
+                            return Err(Error::CliArgs("Bad args : unknown index".to_string()));
+                        }
+                    }
+                } else {
+                    return Err(Error::CliArgs("Bad args : --index".to_string()))
+                }
+            },
+            "--aggregation" => {
+                if i + 1 < args.len() {
+                    aggregation = match ChunkAggregation::from_name(&args[i + 1]) {
+                        Some(aggregation) => aggregation,
+                        None => {
+                            return Err(Error::CliArgs("Bad args : unknown aggregation".to_string()));
+                        }
+                    }
+                } else {
+                    return Err(Error::CliArgs("Bad args : --aggregation".to_string()))
+                }
+            },
+            "--no-ignore" => {
+                walk_options.respect_ignore = false;
+            },
+            "--one-file-system" => {
+                walk_options.one_file_system = true;
+            },
+            "--hidden" => {
+                walk_options.hidden = true;
+            },
+            "--max-depth" => {
+                if i + 1 < args.len() {
+                    walk_options.max_depth = match args[i + 1].parse::<usize>() {
+                        Ok(depth) => Some(depth),
+                        Err(_) => {
+                            return Err(Error::CliArgs("Bad args : --max-depth must be an integer".to_string()));
+                        }
+                    }
+                } else {
+                    return Err(Error::CliArgs("Bad args : --max-depth".to_string()))
+                }
+            },
+            "--resume" => {
+                resume = true;
+            },
+            "--dry-run" => {
+                dry_run = true;
+            },
+            "--reindex" => {
+                command = Some(Command::Reindex);
+            },
+            "--prune" => {
+                command = Some(Command::Prune);
+            },
             "--build" => {
                 if i + 2 < args.len() {
                     let level = match args[i + 1].as_str() {
@@ -83,6 +153,9 @@ async fn mainr() -> Result<()> {
                         "name" => {
                             embedding::EmbeddingState::Name
                         },
+                        "metadata" => {
+                            embedding::EmbeddingState::Metadata
+                        },
                         "content" => {
                             embedding::EmbeddingState::Paragraphs(1)
                         },
@@ -103,34 +176,53 @@ async fn mainr() -> Result<()> {
                             return Err(Error::CliArgs("Bad args : unknown level".to_string()));
                         }
                     };
-                    let target = args[i + 2].as_str();
-                    let cache_path = match cache_path {
-                        Some(path) => path,
-                        None => {
-                            return Err(Error::CliArgs("Bad args : --cache-path is required".to_string()));
-                        }
-                    };
-                    let db_path = match db_path {
-                        Some(ref path) => path.clone(),
-                        None => {
-                            return Err(Error::CliArgs("Bad args : --db-path is required".to_string()));
-                        }
-                    };
-                    build::build(target, level, &cache_path, db_path).await;
-                    return Ok(());
+                    // The number of paragraphs comes before the target, its presence was checked with the level
+                    let target = if args[i + 1] == "paragraphs" { &args[i + 3] } else { &args[i + 2] };
+                    command = Some(Command::Build(level, target.clone()));
+                } else {
+                    return Err(Error::CliArgs("Bad args : --build".to_string()))
                 }
-            }
+            },
             _ => {}
         }
     }
 
-    let mut ui = UI::new(vp, db_path, cache_path);
-    let path = ui.run().await;
+    if let Some(command) = command {
+        // The estimate only walks the target, it needs neither the DB nor the index file
+        if let Command::Build(level, ref target) = command {
+            if dry_run {
+                build::dry_run(target, level, backend, walk_options).await?;
+                return Ok(());
+            }
+        }
+        let cache_path = match cache_path {
+            Some(path) => path,
+            None => {
+                return Err(Error::CliArgs("Bad args : --cache-path is required".to_string()));
+            }
+        };
+        let db_path = match db_path {
+            Some(path) => path,
+            None => {
+                return Err(Error::CliArgs("Bad args : --db-path is required".to_string()));
+            }
+        };
+        match command {
+            Command::Reindex => build::reindex(&cache_path, db_path, backend)?,
+            Command::Prune => build::prune(&cache_path, db_path, backend)?,
+            Command::Build(level, target) => build::build(&target, level, &cache_path, db_path, backend, walk_options, resume).await?
+        }
+        return Ok(());
+    }
+
+    let mut ui = UI::new(vp, db_path, cache_path, backend, walk_options, aggregation);
+    let path = ui.run().await?;
 
     if let Some(path) = path {
         // Write path to target file
         if let Some(target_file) = target_file {
-            std::fs::write(target_file, path.display().to_string()).expect("Can't write to target file");
+            // The raw bytes are written, so that the shell gets the exact path even if it isn't valid UTF-8
+            std::fs::write(target_file, path.as_os_str().as_encoded_bytes()).expect("Can't write to target file");
         }
     }
 
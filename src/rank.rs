@@ -1,8 +1,10 @@
-use std::{path::PathBuf, fs::read_dir, collections::HashMap};
+use std::{path::PathBuf, fs::read_dir, collections::HashMap, sync::Arc};
 
-use crate::error::{Result, Error};
+use crate::error::Result;
+use crate::ignore::Ignore;
+use crate::walk::{WalkFilter, WalkOptions};
 
-use crate::embedding::{Embedder, Task, EmbeddingState, CacheItem};
+use crate::embedding::{Embedder, Task, EmbeddingState, CacheItem, IndexBackend, Chunk, ChunkAggregation, expand_relative_dates};
 
 #[derive(Clone, Copy, Debug)]
 pub enum RankSource {
@@ -16,14 +18,35 @@ pub enum RankSource {
 pub struct RankResult {
     pub path: PathBuf,
     pub source: RankSource,
-    pub score: f32
+    pub score: f32,
+    /// Part of the file matching the input, for semantic results
+    pub chunk: Option<Chunk>,
+    /// Number of other files with the same content
+    pub copies: usize
 }
 impl RankResult {
     pub fn new(path: PathBuf, score: f32, source: RankSource) -> Self {
         Self {
             path: path.canonicalize().unwrap_or(path),
             score,
-            source
+            source,
+            chunk: None,
+            copies: 0
+        }
+    }
+    pub fn with_chunk(mut self, chunk: Chunk) -> Self {
+        self.chunk = Some(chunk);
+        self
+    }
+    pub fn with_copies(mut self, copies: usize) -> Self {
+        self.copies = copies;
+        self
+    }
+    /// Excerpt of the matching passage, if the match comes from the content of the file
+    pub fn passage(&self) -> Option<&str> {
+        match self.chunk {
+            Some(Chunk { range: Some(_), excerpt: Some(ref excerpt), .. }) => Some(excerpt),
+            _ => None
         }
     }
     pub fn is_dir(&self) -> bool {
@@ -34,49 +57,63 @@ impl RankResult {
     }
 }
 
+/// Semantic results less similar than this to the input are ignored
+const MIN_SEMANTIC_SIMILARITY: f32 = 0.2;
 const TASK_NAME_SCORE_LIMIT: f32 = 8.;
 const TASK_PARAGRAPHS_SCORE_LIMIT: f32 = 5.;
 const MAX_TASKS: usize = 100;
-fn walk_path_create_tasks(path: &PathBuf, score: f32, tasks: &mut Vec<Task>) -> Result<()> {
-    if tasks.len() >= MAX_TASKS {
+/// Maximum number of paths checked for tasks, as the paths already embedded and unchanged don't create any
+const MAX_VISITS: usize = 1000;
+/// Collects the candidate tasks of path and of its children, the cache isn't locked as the walk runs on every key press.
+/// rules are the ignore rules of the parent of path, depth is the depth of path under the result the walk started from
+fn walk_path_create_tasks(path: &PathBuf, score: f32, depth: usize, rules: &Arc<Ignore>, filter: &WalkFilter, candidates: &mut Vec<Task>, visits: &mut usize) -> Result<()> {
+    if *visits >= MAX_VISITS {
         return Ok(());
     }
+    *visits += 1;
     if score < TASK_NAME_SCORE_LIMIT {
-        tasks.push(Task::new(CacheItem{ path: path.to_owned(), state: EmbeddingState::Name }, score));
+        candidates.push(Task::new(CacheItem{ path: path.to_owned(), state: EmbeddingState::Name }, score));
     }
     if path.is_dir() && !path.is_symlink() {
+        if !filter.accepts_directory(path, depth) || filter.is_too_deep(depth) {
+            return Ok(());
+        }
         let dir_iter = match read_dir(path.clone()) {
             Ok(dir_iter) => dir_iter,
             Err(e) => {
                 if e.kind() == std::io::ErrorKind::PermissionDenied || e.kind() == std::io::ErrorKind::NotFound || e.raw_os_error() == Some(20) {
                     return Ok(());
                 } else {
-                    panic!("Error {:?} with path {:?}", e, path);
+                    return Err(e.into())
                 }
             }
         };
+        let rules = rules.child(path);
         for entry in dir_iter {
             match entry {
                 Err(e) => {
                     match e.kind() {
                         std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::NotFound => continue,
-                        _ => panic!("Error {:?} with path {:?}", e, path)
+                        _ => return Err(e.into())
                     }
                 },
                 Ok(entry) => {
                     let mut path = entry.path();
+                    if !filter.accepts(&path, path.is_dir(), &rules) {
+                        continue;
+                    }
                     path = match path.canonicalize() {
                         Ok(path) => path,
                         Err(_) => continue
                     };
-                    walk_path_create_tasks(&path, score+1., tasks).unwrap();
+                    walk_path_create_tasks(&path, score+1., depth+1, &rules, filter, candidates, visits)?;
                 }
             }
         }
     } else {
         if score < TASK_PARAGRAPHS_SCORE_LIMIT {
             if score > 0. {
-                tasks.push(Task::new(CacheItem { path: path.to_owned(), state: EmbeddingState::Paragraphs((10./score).round() as usize) }, score+2., ));
+                candidates.push(Task::new(CacheItem { path: path.to_owned(), state: EmbeddingState::Paragraphs((10./score).round() as usize) }, score+2.));
             }
         }
     }
@@ -85,22 +122,32 @@ fn walk_path_create_tasks(path: &PathBuf, score: f32, tasks: &mut Vec<Task>) ->
 
 pub struct Ranker {
     embedder: Embedder,
-    last_input: String
+    last_input: String,
+    /// Options of the listing and of the walks of the directories
+    walk_options: WalkOptions,
+    /// How the similarities of the chunks of a file are combined
+    aggregation: ChunkAggregation
 }
 impl Ranker {
-    pub async fn new(db_path: Option<String>, cache_path: Option<String>) -> Self {
-        Self {
-            embedder: Embedder::new(db_path, cache_path).await,
-            last_input: String::new()
-        }
+    pub fn embedder(&self) -> &Embedder {
+        &self.embedder
+    }
+    pub async fn new(db_path: Option<String>, cache_path: Option<String>, backend: IndexBackend, walk_options: WalkOptions, aggregation: ChunkAggregation) -> Result<Self> {
+        Ok(Self {
+            embedder: Embedder::new(db_path, cache_path, backend).await?,
+            last_input: String::new(),
+            walk_options,
+            aggregation
+        })
     }
 
-    pub fn init(&mut self) {
-        let embedder = self.embedder.clone();
-        embedder.execute_tasks().unwrap();
+    pub fn init(&mut self) -> Result<()> {
+        self.embedder.execute_tasks()
     }
 
-    async fn get_results_hashmap(&mut self, input: &str, result_count: usize) -> HashMap<PathBuf, RankResult> {
+    async fn get_results_hashmap(&mut self, input: &str, result_count: usize) -> Result<HashMap<PathBuf, RankResult>> {
+        // A background task that failed stops the search like the other errors
+        self.embedder.check_tasks().await?;
         let mut results: HashMap<PathBuf, RankResult> = HashMap::new();
 
         let mut input = input.trim();
@@ -115,14 +162,18 @@ impl Ranker {
         // If exact path exists, add it to results
         match path.try_exists() {
             Ok(true) => {
-                results.insert(path.clone().canonicalize().unwrap(), RankResult::new(path.clone(), 0., RankSource::ExactPath));
+                results.insert(path.canonicalize()?, RankResult::new(path.clone(), 0., RankSource::ExactPath));
 
                 // If input is a directory, add all its children to results
                 if path.is_dir() && !path.is_symlink() {
-                    match read_dir(path.clone()) {
+                    // The anchored patterns are relative to the canonical directory of their ignore file, so the entries are joined to the canonical path
+                    let dir = path.canonicalize()?;
+                    let rules = Ignore::for_dir(&dir, self.walk_options.respect_ignore);
+                    let filter = WalkFilter::new(&dir, self.walk_options);
+                    match read_dir(&dir) {
                         Err(e) => {
                             if e.kind() != std::io::ErrorKind::PermissionDenied && e.kind() != std::io::ErrorKind::NotFound {
-                                panic!("Error {:?} with path {:?}", e, path)
+                                return Err(e.into())
                             }
                         },
                         Ok(dir_iter) => {
@@ -130,11 +181,14 @@ impl Ranker {
                                 match entry {
                                     Err(e) => {
                                         if e.kind() != std::io::ErrorKind::PermissionDenied && e.kind() != std::io::ErrorKind::NotFound {
-                                            panic!("Error {:?} with path {:?}", e, path)
+                                            return Err(e.into())
                                         }
                                     },
                                     Ok(entry) => {
-                                        let mut path = entry.path();
+                                        let mut path = dir.join(entry.file_name());
+                                        if !filter.accepts(&path, path.is_dir(), &rules) {
+                                            continue;
+                                        }
                                         path = path.canonicalize().unwrap_or(path);
                                         if let Some(r) = results.get(&path) {
                                             if r.score > 2. {
@@ -153,14 +207,14 @@ impl Ranker {
             Ok(false) => {},
             Err(e) => {
                 if e.kind() != std::io::ErrorKind::PermissionDenied && e.kind() != std::io::ErrorKind::NotFound {
-                    panic!("Error {:?} with path {:?}", e, path)
+                    return Err(e.into())
                 }
             }
         }
 
         // Check if there are paths that starts with input
         if !path.is_dir() && path.exists() {
-            if let Some(dirname) = path.canonicalize().unwrap().parent() {
+            if let Some(dirname) = path.canonicalize()?.parent() {
                 // if path.is_relative() && dirname.to_str().is_some() && dirname.to_str().ok_or(Error::CannotConvertOsStr).unwrap().is_empty() {
                 //     dirname = Path::new(".");
                 // }
@@ -170,19 +224,25 @@ impl Ranker {
                             match entry {
                                 Err(e) => {
                                     if e.kind() != std::io::ErrorKind::PermissionDenied && e.kind() != std::io::ErrorKind::NotFound {
-                                        panic!("Error {:?} with path {:?}", e, path);
+                                        return Err(e.into())
                                     }
                                 },
                                 Ok(entry) => {
                                     let entry_path = entry.path();
-                                    if entry_path.file_name().unwrap().to_str().ok_or(Error::CannotConvertOsStr).unwrap().starts_with(path.file_name().unwrap_or_default().to_str().ok_or(Error::CannotConvertOsStr).unwrap()) {
-                                    
+                                    // Names are compared as bytes, as they may not be valid UTF-8
+                                    let prefix = path.file_name().unwrap_or_default().as_encoded_bytes();
+                                    if entry_path.file_name().map_or(false, |name| name.as_encoded_bytes().starts_with(prefix)) {
+                                        // Broken symlinks can't be canonicalized
+                                        let canonical_path = match entry_path.canonicalize() {
+                                            Ok(canonical_path) => canonical_path,
+                                            Err(_) => continue
+                                        };
                                         if let Some(r) = results.get(&path) {
                                             if r.score > 1. {
-                                                results.insert(entry_path.canonicalize().unwrap(), RankResult::new(entry_path, 1., RankSource::StartLikePath));
+                                                results.insert(canonical_path, RankResult::new(entry_path, 1., RankSource::StartLikePath));
                                             }
                                         } else {
-                                            results.insert(entry_path.canonicalize().unwrap(), RankResult::new(entry_path, 1., RankSource::StartLikePath));
+                                            results.insert(canonical_path, RankResult::new(entry_path, 1., RankSource::StartLikePath));
                                         }
                                     }
                                 }
@@ -191,7 +251,7 @@ impl Ranker {
                     },
                     Err(e) => {
                         if e.kind() != std::io::ErrorKind::PermissionDenied && e.kind() != std::io::ErrorKind::NotFound {
-                            panic!("Error {:?} with path {:?}", e, path)
+                            return Err(e.into())
                         }
                     }
                 }
@@ -201,18 +261,23 @@ impl Ranker {
         // If this is the first time we search for this input, don't check semantic to be faster
         if self.last_input.is_empty() {
             self.last_input = input.to_string();
-            return results;
+            return Ok(results);
         }
 
-        let current_dir = std::env::current_dir().unwrap();
+        let current_dir = std::env::current_dir()?;
         // Check semantic with embedder
-        let nearests = self.embedder.nearest(input, result_count-results.len().min(result_count)).await;
-        for (score, path) in nearests {
+        let nearests = self.embedder.nearest_files(&expand_relative_dates(input), result_count-results.len().min(result_count), self.aggregation).await?;
+        for (similarity, path, chunk, copies) in nearests {
+            if similarity < MIN_SEMANTIC_SIMILARITY {
+                continue;
+            }
+            // in [0, 2], lower is better like the other scores. The aggregated similarity can be greater than 1
+            let score = (1. - similarity).max(0.);
             if let Some(r) = results.get(&path) {
                 if r.score < 3. {
-                    results.insert(r.path.clone(), RankResult::new(path, r.score-1.+score, r.source));
+                    results.insert(r.path.clone(), RankResult::new(path, r.score-1.+score, r.source).with_chunk(chunk).with_copies(copies));
                 } else if r.score > (3. + score) {
-                    results.insert(r.path.clone(), RankResult::new(path, 3.+score, RankSource::Semantic));
+                    results.insert(r.path.clone(), RankResult::new(path, 3.+score, RankSource::Semantic).with_chunk(chunk).with_copies(copies));
                 }
             } else {
                 if path.starts_with(&current_dir) {
@@ -220,16 +285,35 @@ impl Ranker {
                         Ok(path) => path,
                         Err(_) => continue
                     };
-                    results.insert(path.clone(), RankResult::new(path, 3.+score, RankSource::Semantic));
+                    results.insert(path.clone(), RankResult::new(path, 3.+score, RankSource::Semantic).with_chunk(chunk).with_copies(copies));
                 }
             }
         }
 
         // Launch tasks to embed paths in embedder cache
-        let mut tasks = Vec::new();
+        let mut candidates = Vec::new();
+        let mut visits = 0;
+        // Shared by the walks of all the results, so that a directory under several results is walked once
+        let filter = WalkFilter::new(&current_dir, self.walk_options);
         for r in results.values() {
-            walk_path_create_tasks(&r.path, r.score, &mut tasks).unwrap();
+            // The exact path is kept as typed in the results, the walk needs it canonical for the anchored patterns
+            let path = r.path.canonicalize().unwrap_or(r.path.clone());
+            let rules = Ignore::for_dir(path.parent().unwrap_or(&path), self.walk_options.respect_ignore);
+            walk_path_create_tasks(&path, r.score, 0, &rules, &filter, &mut candidates, &mut visits)?;
+        }
+        // Items modified on disk get a task, which hashes them to know if their content changed.
+        // They aren't hashed here, as the cache is locked for the checks
+        let mut tasks = Vec::new();
+        let cache = self.embedder.cache.lock().await;
+        for task in candidates {
+            if tasks.len() >= MAX_TASKS {
+                break;
+            }
+            if !cache.contains_unmodified(task.item())? {
+                tasks.push(task);
+            }
         }
+        drop(cache);
 
         if self.last_input != input {
             self.embedder.set_tasks(tasks.into()).await;
@@ -239,11 +323,11 @@ impl Ranker {
 
         self.last_input = input.to_string();
 
-        results
+        Ok(results)
     }
 
-    pub async fn get_results(&mut self, input: &str, result_count: usize) -> Vec<RankResult> {
-        let results_hashmap = self.get_results_hashmap(input, result_count).await;
+    pub async fn get_results(&mut self, input: &str, result_count: usize) -> Result<Vec<RankResult>> {
+        let results_hashmap = self.get_results_hashmap(input, result_count).await?;
         
         let mut results: Vec<RankResult> = results_hashmap.into_values().collect();
 
@@ -260,6 +344,6 @@ impl Ranker {
             results.truncate(result_count);
         }
 
-        results.to_vec()
+        Ok(results.to_vec())
     }
 }
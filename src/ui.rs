@@ -3,6 +3,9 @@ use crossterm::{terminal::{self, ClearType}, event::{self, KeyEvent, Event, KeyC
 use tokio::sync::RwLock;
 use crate::rank::{RankResult, RankSource};
 use crate::rank::Ranker;
+use crate::embedding::{IndexBackend, ChunkAggregation};
+use crate::walk::WalkOptions;
+use crate::error::{Result, Error};
 pub mod visual_pack;
 use visual_pack::{VisualPack, VisualPackChars};
 use dirs::home_dir;
@@ -10,7 +13,8 @@ use dirs::home_dir;
 #[derive(Clone)]
 enum QuittingReason {
     Success(PathBuf),
-    UserAbort
+    UserAbort,
+    Failure(Arc<Error>)
 }
 
 #[derive(Clone)]
@@ -31,11 +35,14 @@ pub struct UI {
     vp: VisualPack,
     results: Arc<RwLock<Vec<RankResult>>>,
     db_path: Option<String>,
-    cache_path: Option<String>
+    cache_path: Option<String>,
+    backend: IndexBackend,
+    walk_options: WalkOptions,
+    aggregation: ChunkAggregation
 }
 
 impl UI {
-    pub fn new(visual_pack: VisualPack, db_path: Option<String>, cache_path: Option<String>) -> Self {
+    pub fn new(visual_pack: VisualPack, db_path: Option<String>, cache_path: Option<String>, backend: IndexBackend, walk_options: WalkOptions, aggregation: ChunkAggregation) -> Self {
         let input_offset = (visual_pack.get_symbol(VisualPackChars::SearchBarLeft).chars().count()+1) as u16;
         let result_offset = (visual_pack.get_symbol(VisualPackChars::ResultLeft(RankSource::ExactPath, false)).chars().count()+2) as u16;
         Self {
@@ -48,15 +55,18 @@ impl UI {
             vp: visual_pack,
             results: Arc::new(RwLock::new(Vec::new())),
             db_path,
-            cache_path
+            cache_path,
+            backend,
+            walk_options,
+            aggregation
         }
     }
 
     pub fn default() -> Self {
-        Self::new(VisualPack::ExtendedUnicode, None, None)
+        Self::new(VisualPack::ExtendedUnicode, None, None, IndexBackend::Hnsw, WalkOptions::default(), ChunkAggregation::default())
     }
 
-    pub async fn run(&mut self) -> Option<PathBuf> {
+    pub async fn run(&mut self) -> Result<Option<PathBuf>> {
         self.init();
         *self.state.write().await = UIState::Searching;
 
@@ -79,11 +89,31 @@ impl UI {
         let input = self.input.clone();
         let db_path = self.db_path.clone();
         let cache_path = self.cache_path.clone();
+        let backend = self.backend;
+        let walk_options = self.walk_options;
+        let aggregation = self.aggregation;
+        let state = self.state.clone();
+        let embedder = Arc::new(RwLock::new(None));
+        let ranker_embedder = embedder.clone();
         tokio::spawn(async move {
-            let mut ranker = Ranker::new(db_path, cache_path).await;
-            ranker.init();
+            // Errors stop the UI, so that the terminal is restored before they are shown
+            let mut ranker = match Ranker::new(db_path, cache_path, backend, walk_options, aggregation).await {
+                Ok(ranker) => ranker,
+                Err(e) => {
+                    *state.write().await = UIState::Quitting(QuittingReason::Failure(Arc::new(e)));
+                    return;
+                }
+            };
+            *ranker_embedder.write().await = Some(ranker.embedder().clone());
+            if let Err(e) = ranker.init() {
+                *state.write().await = UIState::Quitting(QuittingReason::Failure(Arc::new(e)));
+                return;
+            }
             loop {
-                Self::rank(&results, &input, &mut ranker).await
+                if let Err(e) = Self::rank(&results, &input, &mut ranker).await {
+                    *state.write().await = UIState::Quitting(QuittingReason::Failure(Arc::new(e)));
+                    return;
+                }
             }
         });
 
@@ -103,9 +133,15 @@ impl UI {
         loop {
             if let UIState::Quitting(qr) = (*self.state.read().await).clone() {
                 Writer::clear_screen();
+                // The vectors of this session not saved yet are indexed by the next one if another process holds the lock
+                let flushed = match *embedder.read().await {
+                    Some(ref embedder) => embedder.cache.lock().await.flush().map(|_| ()),
+                    None => Ok(())
+                };
                 match qr {
-                    QuittingReason::Success(p) => return Some(p),
-                    QuittingReason::UserAbort => return None
+                    QuittingReason::Success(p) => return flushed.map(|_| Some(p)),
+                    QuittingReason::UserAbort => return flushed.map(|_| None),
+                    QuittingReason::Failure(e) => return Err(Error::Arced(e))
                 }
             }
         }
@@ -216,11 +252,12 @@ impl UI {
         };
     }
 
-    async fn rank(results: &Arc<RwLock<Vec<RankResult>>>, input: &Arc<RwLock<String>>, ranker: &mut Ranker) {
+    async fn rank(results: &Arc<RwLock<Vec<RankResult>>>, input: &Arc<RwLock<String>>, ranker: &mut Ranker) -> Result<()> {
         let input = input.read().await.clone();
         let result_count = terminal::size().expect("Can't get terminal size").1 as usize - 3;
 
-        *results.write().await = ranker.get_results(&input, result_count).await;
+        *results.write().await = ranker.get_results(&input, result_count).await?;
+        Ok(())
     }
 
     async fn render(vp: VisualPack, writer: &mut Writer, display_input: &Arc<RwLock<String>>, results: &Arc<RwLock<Vec<RankResult>>>, cursor: &[Arc<AtomicU16>; 2], input_offset: u16, result_offset: u16) {
@@ -230,29 +267,50 @@ impl UI {
         let mut output_text = format!(" {}{}{}\r\n", vp.get_colored_symbol(VisualPackChars::SearchBarLeft), display_input.read().await, vp.get_colored_symbol(VisualPackChars::SearchBarRight));
 
         let current_dir = std::env::current_dir().expect("Can't get current dir");
-        let current_path = current_dir.to_str().unwrap_or("");
-
-        let home_dir = match home_dir() {
-            Some(p) => p.to_str().unwrap_or("").to_owned(),
-            None => "".to_string()
-        };
+        let home_dir = home_dir();
 
         let terminal_width = terminal_size.0;
         for (i, result) in results.read().await.iter().enumerate() {
             let symbol = vp.get_colored_symbol(VisualPackChars::ResultLeft(result.source, result.is_dir()));
-            let mut path = result.path.display().to_string();
-            if path.starts_with(current_path) && path != current_path && current_dir != Path::new("/") {
-                path = path.replacen(current_path, ".", 1);
-            } else if path.starts_with(&home_dir) && path != home_dir {
-                path = path.replacen(&home_dir, "~", 1);
-            }
+            // Paths are compared by components, and only converted lossily to be displayed
+            let mut path = match (result.path.strip_prefix(&current_dir), home_dir.as_ref().map(|home| result.path.strip_prefix(home))) {
+                (Ok(relative), _) if !relative.as_os_str().is_empty() && current_dir != Path::new("/") => Path::new(".").join(relative).display().to_string(),
+                (_, Some(Ok(relative))) if !relative.as_os_str().is_empty() => Path::new("~").join(relative).display().to_string(),
+                _ => result.path.display().to_string()
+            };
 
             // If path is to long to fit on one line, replace the start with "…"
-            if path.len() as u16 + result_offset > terminal_width {
-                path = "…".to_string() + &path[(path.len() as u16 + result_offset - terminal_width + 1) as usize..];
+            // Counted in chars, a lossily converted name contains multibyte replacement characters
+            let path_width = path.chars().count() as u16;
+            if path_width + result_offset > terminal_width {
+                path = "…".to_string() + &path.chars().skip((path_width + result_offset - terminal_width + 1) as usize).collect::<String>();
             }
 
             let mut line = format!("\r\n {} {}", symbol, path);
+            let mut line_width = path.chars().count() as u16 + result_offset;
+
+            if result.copies > 0 {
+                let copies = format!(" ({} other cop{})", result.copies, if result.copies == 1 { "y" } else { "ies" });
+                if line_width + copies.chars().count() as u16 <= terminal_width {
+                    line_width += copies.chars().count() as u16;
+                    line.push_str(&copies.dim().to_string());
+                }
+            }
+
+            // Show the matching passage after the path if there is room left on the line
+            if let Some(passage) = result.passage() {
+                let room = terminal_width.saturating_sub(line_width + 3) as usize;
+                if room > 1 {
+                    let passage = passage.split_whitespace().collect::<Vec<_>>().join(" ");
+                    let passage = if passage.chars().count() > room {
+                        passage.chars().take(room-1).collect::<String>() + "…"
+                    } else {
+                        passage
+                    };
+                    line.push_str(&format!("  {}", passage.dim()));
+                }
+            }
+
             if cursor[1].load(Ordering::Relaxed) == (i as u16+1) {
                 line = line.on_dark_grey().to_string();
             }
@@ -263,7 +321,7 @@ impl UI {
             output_text.push('\n');
         }
 
-        output_text.push_str(&format!("\r\n {}", current_path).on_dark_grey().to_string());
+        output_text.push_str(&format!("\r\n {}", current_dir.display()).on_dark_grey().to_string());
 
         writer.write(&output_text, [cursor[0].load(Ordering::Relaxed)+input_offset, 0]);
     }
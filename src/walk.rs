@@ -0,0 +1,128 @@
+/// Options and checks shared by the directory walks of the build and of the ranking
+
+use std::{path::{Path, PathBuf}, collections::HashSet, fs::{self, Metadata}, sync::Mutex};
+use crate::ignore::Ignore;
+
+/// Types of the filesystems whose files describe the system instead of containing documents
+const VIRTUAL_FILESYSTEMS: [&str; 9] = ["proc", "sysfs", "devtmpfs", "devpts", "cgroup", "cgroup2", "debugfs", "tracefs", "securityfs"];
+
+#[derive(Clone, Copy, Debug)]
+pub struct WalkOptions {
+    /// Skip the items matching the ignore files and the global exclude list
+    pub respect_ignore: bool,
+    /// Don't walk the directories on an other filesystem than the root of the walk
+    pub one_file_system: bool,
+    /// Depth under the root of the walk below which directories aren't read, None for no limit
+    pub max_depth: Option<usize>,
+    /// Include the items whose name starts with a dot
+    pub hidden: bool
+}
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            respect_ignore: true,
+            one_file_system: false,
+            max_depth: None,
+            hidden: false
+        }
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Mount points of the virtual filesystems, read from /proc/self/mounts where the spaces of the paths are written `\040`
+fn virtual_mount_points() -> HashSet<PathBuf> {
+    let mounts = match fs::read_to_string("/proc/self/mounts") {
+        Ok(mounts) => mounts,
+        Err(_) => return HashSet::new()
+    };
+    mounts.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mount_point = fields.nth(1)?;
+            let filesystem = fields.next()?;
+            VIRTUAL_FILESYSTEMS.contains(&filesystem).then(|| PathBuf::from(unescape_mount_point(mount_point)))
+        })
+        .collect()
+}
+
+fn unescape_mount_point(mount_point: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = mount_point;
+    while let Some(i) = rest.find('\\') {
+        unescaped.push_str(&rest[..i]);
+        match rest.get(i + 1..i + 4).and_then(|octal| u8::from_str_radix(octal, 8).ok()) {
+            Some(c) => {
+                unescaped.push(c as char);
+                rest = &rest[i + 4..];
+            },
+            None => {
+                unescaped.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Decides which items of a walk are visited. A walk can be shared by several tasks
+pub struct WalkFilter {
+    pub options: WalkOptions,
+    /// Device of the root of the walk, for one_file_system
+    root_device: Option<u64>,
+    virtual_mount_points: HashSet<PathBuf>,
+    /// Devices and inodes of the directories already walked, so that a symlink or a bind mount to an ancestor doesn't loop
+    visited: Mutex<HashSet<(u64, u64)>>
+}
+impl WalkFilter {
+    pub fn new(root: &Path, options: WalkOptions) -> Self {
+        Self {
+            options,
+            root_device: fs::metadata(root).ok().as_ref().and_then(file_id).map(|(device, _)| device),
+            virtual_mount_points: virtual_mount_points(),
+            visited: Mutex::new(HashSet::new())
+        }
+    }
+
+    /// Returns false for the hidden items if they aren't included and for the items matching the rules
+    pub fn accepts(&self, path: &Path, is_dir: bool, rules: &Ignore) -> bool {
+        let hidden = path.file_name().map_or(false, |name| name.as_encoded_bytes().starts_with(b"."));
+        (self.options.hidden || !hidden) && !rules.is_ignored(path, is_dir)
+    }
+
+    /// Returns false if the directory, at depth under the root, was already walked, is on a virtual filesystem or on an other filesystem when they are excluded.
+    /// The root itself is always walked
+    pub fn accepts_directory(&self, dir: &Path, depth: usize) -> bool {
+        if depth > 0 && self.virtual_mount_points.contains(dir) {
+            return false;
+        }
+        let id = match fs::metadata(dir) {
+            Ok(metadata) => file_id(&metadata),
+            Err(_) => return false
+        };
+        if let Some((device, inode)) = id {
+            if depth > 0 && self.options.one_file_system && self.root_device.map_or(false, |root_device| root_device != device) {
+                return false;
+            }
+            let mut visited = self.visited.lock().unwrap_or_else(|e| e.into_inner());
+            if !visited.insert((device, inode)) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns true if the children of a directory at depth are too deep to be walked
+    pub fn is_too_deep(&self, depth: usize) -> bool {
+        self.options.max_depth.map_or(false, |max_depth| depth >= max_depth)
+    }
+}